@@ -0,0 +1,36 @@
+// Per node overhead of mutating, which short sequences on a tree with
+// thousands of internal nodes are dominated by. Changes are compared by
+// running `cargo bench --bench allocation -- --save-baseline before` on
+// the old code and `-- --baseline before` on the new.
+mod common;
+
+use aminosim::mutator::Mutator;
+use aminosim::SimulationOptions;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use std::hint::black_box;
+
+fn per_node(c: &mut Criterion) {
+    let model = common::hky();
+
+    // 8191 internal nodes
+    let newick = common::balanced_newick(13);
+    let mut trees = vec![common::built_tree(&newick, 50)];
+    let opts = SimulationOptions::new(1);
+    c.bench_function("simulate 8192 tips, 50 sites", |b| {
+        b.iter(|| black_box(aminosim::simulate(&mut trees, &model, &opts)
+            .unwrap()))
+    });
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let seq = model.random(50, &mut rng);
+    c.bench_function("HKY::mutate, 50 sites", |b| {
+        b.iter(|| black_box(model.mutate(&seq, 0.01, &mut rng).unwrap()))
+    });
+}
+
+criterion_group!(benches, per_node);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+// Trees and models shared by the benchmarks
+use aminosim::mutator::HKY;
+use aminosim::tree::{NegativeBranches, NTree};
+
+// Newick string of a balanced tree with 2^depth tips t0, t1...
+pub fn balanced_newick(depth: u32) -> String {
+    fn subtree(depth: u32, next: &mut usize, out: &mut String) {
+        if depth == 0 {
+            out.push_str(&format!("t{}:0.01", next));
+            *next += 1;
+            return;
+        }
+
+        out.push('(');
+        subtree(depth - 1, next, out);
+        out.push(',');
+        subtree(depth - 1, next, out);
+        out.push_str("):0.01");
+    }
+
+    let mut newick = String::new();
+    subtree(depth, &mut 0, &mut newick);
+    newick.push(';');
+    newick
+}
+
+pub fn built_tree(newick: &str, partition: usize) -> NTree {
+    let mut tree = NTree::new(partition, newick.to_string());
+    tree.build_from_newick(NegativeBranches::Error).unwrap();
+    tree
+}
+
+pub fn hky() -> HKY {
+    HKY::new(0.25, 0.25, 0.25, 0.25, b'A', b'G', b'C', b'T', 2.0, 1.0)
+        .unwrap()
+}
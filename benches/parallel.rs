@@ -0,0 +1,40 @@
+// Speed-up of evolving a single large tree on more threads, as subtrees
+// are evolved on their own rayon tasks. Run with `cargo bench --bench
+// parallel`.
+mod common;
+
+use aminosim::SimulationOptions;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rayon::ThreadPoolBuilder;
+
+use std::hint::black_box;
+
+fn single_tree(c: &mut Criterion) {
+    let model = common::hky();
+    let newick = common::balanced_newick(12);
+    let mut trees = vec![common::built_tree(&newick, 2000)];
+    let opts = SimulationOptions::new(1);
+
+    let max_threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get());
+    let mut threads = vec![1, 2, 4, max_threads];
+    threads.retain(|&n| n <= max_threads);
+    threads.dedup();
+
+    let mut group = c.benchmark_group("simulate 4096 tips, 2000 sites");
+    group.sample_size(10);
+    for n in threads {
+        let pool = ThreadPoolBuilder::new().num_threads(n).build().unwrap();
+        group.bench_with_input(BenchmarkId::new("threads", n), &n, |b, _| {
+            b.iter(|| pool.install(|| {
+                black_box(aminosim::simulate(&mut trees, &model, &opts)
+                    .unwrap())
+            }))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, single_tree);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+// Timings of tree parsing, mutation and whole simulations, run with
+// `cargo bench --bench simulation`.
+mod common;
+
+use aminosim::mutator::Mutator;
+use aminosim::SimulationOptions;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use std::hint::black_box;
+
+fn parsing(c: &mut Criterion) {
+    let large = common::balanced_newick(14);
+    let mut group = c.benchmark_group("build_from_newick");
+    group.sample_size(10);
+    group.bench_function("16384 tips", |b| {
+        b.iter(|| black_box(common::built_tree(&large, 1)))
+    });
+    group.finish();
+}
+
+fn mutation(c: &mut Criterion) {
+    let model = common::hky();
+    let mut rng = StdRng::seed_from_u64(1);
+    let seq = model.random(100_000, &mut rng);
+
+    let mut group = c.benchmark_group("HKY::mutate, 100000 sites");
+    for &v in &[0.01, 0.1, 1.0] {
+        group.bench_with_input(BenchmarkId::new("v", v), &v, |b, &v| {
+            b.iter(|| black_box(model.mutate(&seq, v, &mut rng).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+fn simulation(c: &mut Criterion) {
+    let model = common::hky();
+    let moderate = common::balanced_newick(10);
+    let mut trees = vec![common::built_tree(&moderate, 1000)];
+    let opts = SimulationOptions::new(1);
+
+    let mut group = c.benchmark_group("simulate");
+    group.sample_size(10);
+    group.bench_function("1024 tips, 1000 sites", |b| {
+        b.iter(|| black_box(aminosim::simulate(&mut trees, &model, &opts)
+            .unwrap()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing, mutation, simulation);
+criterion_main!(benches);
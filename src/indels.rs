@@ -0,0 +1,84 @@
+use crate::mutator::Mutator;
+use crate::sequence::Sequence;
+
+use rand::Rng;
+use rand::rngs::StdRng;
+
+// Insertions and deletions of stretches of sites along branches. Events
+// happen at 'rate' per site per unit of branch length, as a birth-death
+// process over the sequence: half are insertions of random sites, drawn
+// from the model's frequencies (and mixture components from their weights),
+// and half deletions. Their lengths, in sites (codons for codon models),
+// are geometric with mean 'mean_length'.
+// Homology isn't tracked, so sequences end up with different lengths and
+// are unaligned.
+#[derive(Clone, Copy)]
+pub struct IndelModel {
+    rate: f64,
+    mean_length: f64
+}
+
+impl IndelModel {
+    pub fn new(rate: f64, mean_length: f64) -> Result<IndelModel, String> {
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("Indel rate must be a non-negative number, \
+                got {}", rate));
+        }
+        if !mean_length.is_finite() || mean_length < 1.0 {
+            return Err(format!("Mean indel length must be at least 1, \
+                got {}", mean_length));
+        }
+
+        Ok(IndelModel { rate, mean_length })
+    }
+
+    // Settings by name
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("indel_rate", self.rate), ("indel_length", self.mean_length)]
+    }
+
+    // Apply the indels happening along a branch of length 'v' to 's'.
+    // Events are drawn one at a time, as each one changes the sequence's
+    // length and so the rate of the next. There's one more insertion point
+    // than sites, so even empty sequences can grow back.
+    pub fn evolve(&self, s: &mut Sequence, v: f64, m: &dyn Mutator,
+        rng: &mut StdRng) {
+        let width = m.site_width();
+        let mut t: f64 = 0.0;
+
+        loop {
+            let sites = s.len() / width;
+            let total_rate = self.rate * (sites + 1) as f64;
+            if total_rate <= 0.0 {
+                return;
+            }
+
+            t += -(1.0 - rng.gen::<f64>()).ln() / total_rate;
+            if t >= v {
+                return;
+            }
+
+            let length = self.draw_length(rng);
+            if rng.gen::<bool>() {
+                let at = rng.gen_range(0, sites + 1);
+                let inserted = m.random(length, rng);
+                s.insert(at * width, &inserted);
+            } else if sites > 0 {
+                let start = rng.gen_range(0, sites);
+                let end = (start + length).min(sites);
+                s.delete(start * width..end * width);
+            }
+        }
+    }
+
+    // Geometric length, at least 1, with mean 'mean_length'
+    fn draw_length(&self, rng: &mut StdRng) -> usize {
+        let p = 1.0 / self.mean_length;
+        if p >= 1.0 {
+            return 1;
+        }
+
+        let u = 1.0 - rng.gen::<f64>();
+        1 + (u.ln() / (1.0 - p).ln()).floor() as usize
+    }
+}
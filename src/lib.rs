@@ -0,0 +1,385 @@
+pub mod aa_models;
+pub mod indels;
+pub mod parsers;
+pub mod tree;
+pub mod sequence;
+pub mod mutator;
+pub mod output;
+pub mod rates;
+pub mod stats;
+
+use crate::indels::IndelModel;
+use crate::sequence::Sequence;
+use crate::mutator::{MutationError, Mutator};
+use crate::rates::RateHeterogeneity;
+use crate::tree::{random_root, EvolveOptions, NTree};
+
+use rayon::prelude::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+
+// Symbol filling the sites of taxa missing from a tree
+const GAP: char = '-';
+
+// Sequences of a tree, by node id, and the tree's index in the input
+type Partition = (usize, HashMap<String, Sequence>);
+
+// Settings for a simulation run besides the trees and the model
+pub struct SimulationOptions {
+    // The i-th tree is simulated with an RNG seeded with seed + i, and
+    // every node's RNG is seeded from its parent's, so results are the same
+    // whatever the number of threads
+    pub seed: u64,
+    // Index in the input of the first of the trees simulated, when they're
+    // only some of the input's, so each gets the seed of a full run
+    pub first_tree: usize,
+    pub site_rates: RateHeterogeneity,
+    // Rates of each tree's sites, overriding 'site_rates'
+    pub tree_site_rates: Option<Vec<RateHeterogeneity>>,
+    // Also output the sequences of internal nodes
+    pub ancestral: bool,
+    // Name unlabeled tips tip_N instead of failing
+    pub auto_tip_ids: bool,
+    // Branches shorter than this aren't mutated along, 0 mutates them all
+    pub branch_min: f64,
+    // Pad taxa missing from some trees with gaps, so all sequences have
+    // the same length
+    pub fill_missing: bool,
+    // Insertions and deletions along branches. Sequences then have
+    // different lengths, and aren't aligned.
+    pub indels: Option<IndelModel>,
+    // Draw progress bars on stderr, if it's a terminal
+    pub progress: bool,
+    // Draw a single random root sequence spanning every tree and give each
+    // tree its slice, rather than drawing a root per tree. Ignored if
+    // 'root_sequences' are given.
+    pub shared_root: bool,
+    // Root sequence of each tree, random ones are drawn if None
+    pub root_sequences: Option<Vec<Sequence>>,
+    // Count the sites substituted along every branch, see
+    // NTree::branch_substitutions. Internal nodes are labeled as with
+    // 'ancestral'.
+    pub count_substitutions: bool,
+    // Mutate every root sequence along a branch this long before evolving
+    // its tree, so random roots start closer to the model's equilibrium
+    // frequencies. 0 leaves roots as drawn.
+    pub burnin: f64,
+    // Frequencies random roots are drawn from, in the model's alphabet
+    // order, instead of the model's equilibrium ones. Only single symbol
+    // models are supported, and substitutions still follow the model.
+    pub root_frequencies: Option<Vec<f64>>
+}
+
+impl SimulationOptions {
+    pub fn new(seed: u64) -> SimulationOptions {
+        SimulationOptions {
+            seed,
+            first_tree: 0,
+            site_rates: RateHeterogeneity::uniform(),
+            tree_site_rates: None,
+            ancestral: false,
+            auto_tip_ids: false,
+            branch_min: 0.0,
+            fill_missing: false,
+            indels: None,
+            progress: false,
+            shared_root: false,
+            root_sequences: None,
+            count_substitutions: false,
+            burnin: 0.0,
+            root_frequencies: None
+        }
+    }
+}
+
+// Create ancestral sequences for every tree, evolve them down the trees
+// and concatenate each taxon's sequences across trees, in tree order
+pub fn simulate(trees: &mut [NTree], model: &dyn Mutator,
+    opts: &SimulationOptions) ->
+    Result<BTreeMap<String, String>, MutationError> {
+    let models = vec![model; trees.len()];
+    simulate_partitioned(trees, &models, opts)
+}
+
+// Same as 'simulate', but the i-th tree evolves under the i-th model
+// Trees are left without sequences, and can be simulated again.
+pub fn simulate_partitioned(trees: &mut [NTree], models: &[&dyn Mutator],
+    opts: &SimulationOptions) ->
+    Result<BTreeMap<String, String>, MutationError> {
+    prepare_trees(trees, models, opts);
+
+    let shared = shared_roots(trees, models, opts);
+    let roots = shared.as_deref().or(opts.root_sequences.as_deref());
+
+    info!("Evolving {} trees...", trees.len());
+    let bars = opts.progress.then(|| (
+        progress_bar("Ancestral", trees.len()),
+        progress_bar("Evolving", trees.len())));
+    let mut mutated_seqs = match &bars {
+        Some((ancestral, evolved)) => {
+            let seqs = evolve_trees(trees, models, 0, roots, opts,
+                Some(ancestral), Some(evolved));
+            evolved.finish();
+            seqs?
+        },
+        None => evolve_trees(trees, models, 0, roots, opts, None, None)?
+    };
+
+    // Assemble mutant partitions, columns in input order
+    info!("Assembling mutants...");
+    mutated_seqs.sort_by_key(|&(i, _)| i);
+    let mut assembled_seqs: BTreeMap<String, String> = mutated_seqs.iter()
+        .flat_map(|(_, h)| h.keys())
+        .map(|k| (k.clone(), String::new()))
+        .collect();
+    // Taxa missing from some tree
+    let mut ragged = BTreeSet::<String>::new();
+    for (_, h) in mutated_seqs {
+        // Sequences of a tree only differ in length with indels, taxa
+        // missing from it are padded to the longest
+        let width = h.values().map(|v| v.len()).max()
+            .unwrap_or(0);
+        for (k, s) in assembled_seqs.iter_mut() {
+            match h.get(k) {
+                Some(v) => s.push_str(v.as_str()),
+                None    => {
+                    fill_gaps(s, width, opts.fill_missing);
+                    ragged.insert(k.clone());
+                }
+            }
+        }
+    }
+
+    if !ragged.is_empty() {
+        let some: Vec<&str> = ragged.iter().take(5).map(|k| k.as_str())
+            .collect();
+        if opts.fill_missing {
+            info!("Filled the gaps of {} taxa missing from some trees \
+                (e.g. {})", ragged.len(), some.join(", "));
+        } else {
+            warn!("{} taxa are missing from some trees, so their sequences \
+                are shorter (e.g. {}), fill them with gaps to get an \
+                alignment", ragged.len(), some.join(", "));
+        }
+    }
+
+    Ok(assembled_seqs)
+}
+
+// Same as 'simulate_partitioned', but instead of assembling everything in
+// memory the sequences of each tree are handed to 'sink', in tree order.
+// Only 'batch' trees hold sequences at any time, so memory use is bounded
+// by the batch rather than by the whole alignment. Results are the same as
+// 'simulate_partitioned' for the same seed. Sequences that can't be mutated
+// are reported as InvalidData errors.
+pub fn simulate_streaming<F>(trees: &mut [NTree], models: &[&dyn Mutator],
+    opts: &SimulationOptions, batch: usize, mut sink: F) -> io::Result<()>
+where F: FnMut(HashMap<String, Sequence>) -> io::Result<()>, {
+    assert!(batch > 0, "Batches need at least one tree");
+    prepare_trees(trees, models, opts);
+    let shared = shared_roots(trees, models, opts);
+    let roots = shared.as_deref().or(opts.root_sequences.as_deref());
+
+    info!("Evolving {} trees in batches of {}...", trees.len(), batch);
+    // Batches alternate between phases, so a single bar counts evolved trees
+    let bar = opts.progress.then(|| progress_bar("Evolving", trees.len()));
+    let mut first: usize = 0;
+    for batch_trees in trees.chunks_mut(batch) {
+        let take = batch_trees.len();
+        let batch_models = &models[first..first + take];
+
+        let mut mutated_seqs = evolve_trees(batch_trees, batch_models, first,
+            roots, opts, None, bar.as_ref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        mutated_seqs.sort_by_key(|&(i, _)| i);
+        for (_, h) in mutated_seqs {
+            sink(h)?;
+        }
+
+        first += take;
+    }
+
+    if let Some(bar) = bar {
+        bar.finish();
+    }
+
+    Ok(())
+}
+
+// Ids of every sequence simulating 'trees' outputs, in the order they first
+// appear in a preorder traversal of each tree in turn. Node labels given by
+// 'opts' are only there once 'trees' have been simulated.
+pub fn taxon_order(trees: &[NTree], opts: &SimulationOptions) ->
+    Vec<String> {
+    let mut seen = HashSet::<String>::new();
+    trees.iter()
+        .flat_map(|t| t.output_ids(opts.ancestral))
+        .filter(|id| seen.insert(id.clone()))
+        .collect()
+}
+
+// Remove the sites, 'width' columns each, where every sequence of the
+// alignment 'seqs' has the same symbols, returning how many were removed
+pub fn remove_invariant_sites(seqs: &mut [(String, String)], width: usize) ->
+    usize {
+    let len = match seqs.first() {
+        Some((_, s)) => s.len(),
+        None         => return 0
+    };
+    assert!(seqs.iter().all(|(_, s)| s.len() == len),
+        "Can't remove invariant sites of unaligned sequences");
+
+    let first = seqs[0].1.as_bytes();
+    let variable: Vec<bool> = first.chunks(width).enumerate()
+        .map(|(i, site)| seqs.iter().any(|(_, s)|
+            &s.as_bytes()[i * width..i * width + site.len()] != site))
+        .collect();
+
+    for (_, s) in seqs.iter_mut() {
+        *s = s.as_bytes().chunks(width).zip(&variable)
+            .filter(|(_, &v)| v)
+            .flat_map(|(site, _)| site.iter().map(|&b| b as char))
+            .collect();
+    }
+
+    variable.iter().filter(|&&v| !v).count()
+}
+
+// Append 'width' gaps to 's' if 'fill' is set
+fn fill_gaps(s: &mut String, width: usize, fill: bool) {
+    if fill {
+        s.extend(std::iter::repeat_n(GAP, width));
+    }
+}
+
+// Label nodes as requested in 'opts' and check there's a model (and root
+// sequence, if given) per tree
+fn prepare_trees(trees: &mut [NTree], models: &[&dyn Mutator],
+    opts: &SimulationOptions) {
+    assert_eq!(trees.len(), models.len(), "Need one model per tree");
+    if let Some(roots) = &opts.root_sequences {
+        assert_eq!(trees.len(), roots.len(), "Need one root per tree");
+    }
+
+    if opts.ancestral || opts.count_substitutions {
+        trees.par_iter_mut().for_each(|t| t.label_internal_nodes());
+    }
+    if opts.auto_tip_ids {
+        trees.par_iter_mut().for_each(|t| t.label_tips());
+    }
+}
+
+// Slices of a single random sequence as long as all of 'trees' together,
+// one per tree, if 'opts' asks for a shared root and gives no roots. The
+// sequence is drawn from the first tree's model with an RNG seeded with
+// the bitwise complement of the seed, which no tree's RNG uses.
+fn shared_roots(trees: &[NTree], models: &[&dyn Mutator],
+    opts: &SimulationOptions) -> Option<Vec<Sequence>> {
+    if !opts.shared_root || opts.root_sequences.is_some() || trees.is_empty() {
+        return None;
+    }
+
+    let width = models[0].site_width();
+    let sites: usize = trees.iter().map(|t| t.get_partition()).sum();
+    let mut rng = StdRng::seed_from_u64(!opts.seed);
+    let full = random_root(models[0], sites,
+        opts.root_frequencies.as_deref(), &mut rng);
+
+    let mut start: usize = 0;
+    Some(trees.iter().map(|t| {
+        let end = start + t.get_partition() * width;
+        let root = full.slice(start, end);
+        start = end;
+        root
+    }).collect())
+}
+
+// Create ancestral sequences for 'trees', which start at index 'first' of
+// the input, from 'roots' if given (which cover the whole input), and
+// evolve them, returning the sequences of each tree along
+// with its index in the input. Trees are counted on the given progress bars
+// as they finish each phase, the ancestral bar is finished here.
+fn evolve_trees(trees: &mut [NTree], models: &[&dyn Mutator], first: usize,
+    roots: Option<&[Sequence]>, opts: &SimulationOptions,
+    ancestral_bar: Option<&ProgressBar>,
+    evolve_bar: Option<&ProgressBar>) ->
+    Result<Vec<Partition>, MutationError> {
+    // Every tree owns an RNG seeded with seed + its index in the input, so
+    // results don't depend on how rayon schedules trees across threads
+    let offset = opts.first_tree + first;
+    let mut rngs: Vec<StdRng> = (offset..offset + trees.len())
+        .map(|i| StdRng::seed_from_u64(opts.seed.wrapping_add(i as u64)))
+        .collect();
+
+    let site_rates: Vec<&RateHeterogeneity> = match &opts.tree_site_rates {
+        Some(rates) => rates[first..first + trees.len()].iter().collect(),
+        None        => vec![&opts.site_rates; trees.len()]
+    };
+
+    // Create ancestral sequences
+    match roots {
+        Some(roots) => {
+            let roots = &roots[first..first + trees.len()];
+            trees.par_iter_mut().zip(rngs.par_iter_mut())
+                .zip(roots.par_iter()).zip(models.par_iter())
+                .zip(site_rates.par_iter())
+                .for_each(|((((t, r), s), m), rates)| {
+                    t.set_ancestral(*m, s.clone(), rates, r);
+                    tick(ancestral_bar);
+                });
+        },
+        None => {
+            trees.par_iter_mut().zip(rngs.par_iter_mut())
+                .zip(models.par_iter()).zip(site_rates.par_iter())
+                .for_each(|(((t, r), m), rates)| {
+                    t.create_ancestral(*m, rates,
+                        opts.root_frequencies.as_deref(), r);
+                    tick(ancestral_bar);
+                });
+        }
+    }
+    if let Some(bar) = ancestral_bar {
+        bar.finish();
+    }
+
+    // Evolve all trees
+    let evolve_opts = EvolveOptions {
+        ancestral: opts.ancestral,
+        branch_min: opts.branch_min,
+        indels: opts.indels,
+        count_substitutions: opts.count_substitutions,
+        burnin: opts.burnin
+    };
+    trees.par_iter_mut().zip(rngs.par_iter_mut()).zip(models.par_iter())
+        .enumerate()
+        .map(|(i, ((t, r), m))| {
+            let mut h = HashMap::<String, Sequence>::new();
+            t.dfs_evolve(*m, &mut h, r, evolve_opts)?;
+            tick(evolve_bar);
+            Ok((first + i, h))
+        })
+        .collect()
+}
+
+// Bar on stderr for a phase of a run with 'total' steps, with an estimate
+// of the time left. Only drawn on terminals, logs cover everything else.
+pub fn progress_bar(label: &str, total: usize) -> ProgressBar {
+    let style = ProgressStyle::with_template(
+        "{prefix} [{bar:30}] {pos}/{len} ETA {eta}")
+        .expect("Progress bar template is malformed")
+        .progress_chars("## ");
+    ProgressBar::new(total as u64).with_style(style)
+        .with_prefix(label.to_string())
+}
+
+// Count a step on 'bar', if there's one
+fn tick(bar: Option<&ProgressBar>) {
+    if let Some(bar) = bar {
+        bar.inc(1);
+    }
+}
@@ -1,17 +1,49 @@
-mod parsers;
-mod tree;
-mod sequence;
-mod mutator;
-
-use crate::sequence::Sequence;
+use aminosim::{aa_models, mutator, output, parsers, rates, stats};
+use aminosim::indels::IndelModel;
+use aminosim::mutator::Mutator;
+use aminosim::SimulationOptions;
+use aminosim::sequence::Sequence;
+use aminosim::tree::NegativeBranches;
 
 use rayon::ThreadPoolBuilder;
-use rayon::prelude::*;
 use clap::{Arg, App};
+use log::{debug, info, Level, LevelFilter, Log, Metadata, Record};
 
 use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// Sends log messages to stderr, keeping stdout free for output
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Error => eprintln!("Error: {}", record.args()),
+            Level::Warn  => eprintln!("Warning: {}", record.args()),
+            _            => eprintln!("{}", record.args())
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+// Tips with shorter branches than this are an error under --strict
+const STRICT_TIP_BRANCH: f64 = 1e-8;
+// Largest difference between root to tip distances of an ultrametric tree,
+// relative to its height, leaving room for rounded branch lengths
+const ULTRAMETRIC_TOLERANCE: f64 = 1e-6;
 
 fn main() {
     // Get app info
@@ -24,119 +56,1410 @@ fn main() {
                  .long("treefile")
                  .takes_value(true)
                  .required(true)
-                 .help("File with input coalescent tree(s)"))
+                 .help("File with input coalescent tree(s), one Newick tree \
+                        per line or a NEXUS file with a TREES block, may be \
+                        gzip compressed. Use - to read from stdin"))
         .arg(Arg::with_name("outfile")
                  .short("o")
                  .long("outfile")
                  .takes_value(true)
-                 .required(true)
-                 .help("Output filename"))
+                 .required_unless_one(&["split-partitions", "check"])
+                 .help("Output filename, gzip compressed if it ends in .gz, or \
+                        - for stdout"))
+        .arg(Arg::with_name("format")
+                 .short("f")
+                 .long("format")
+                 .takes_value(true)
+                 .possible_values(&["phylip-like", "phylip", "fasta",
+                                    "nexus"])
+                 .default_value("phylip-like")
+                 .help("Output sequence format"))
+        .arg(Arg::with_name("sort-taxa")
+                 .long("sort-taxa")
+                 .takes_value(true)
+                 .possible_values(&["name", "tree-order"])
+                 .default_value("name")
+                 .help("Write taxa sorted by name, or in the order they \
+                        first appear in the trees"))
+        .arg(Arg::with_name("phylip-interleaved")
+                 .long("phylip-interleaved")
+                 .help("Write PHYLIP output in interleaved blocks"))
+        .arg(Arg::with_name("append")
+                 .long("append")
+                 .conflicts_with("split-partitions")
+                 .help("Add the sequences to the end of the output file \
+                        instead of replacing it"))
+        .arg(Arg::with_name("fasta-width")
+                 .long("fasta-width")
+                 .takes_value(true)
+                 .help("Wrap FASTA sequences every this many columns, 0 \
+                        writes each on a single line [default: 60]"))
         .arg(Arg::with_name("length")
                  .short("l")
                  .long("length")
                  .takes_value(true)
+                 .required_unless("partitions")
+                 .conflicts_with("partitions")
                  .help("Length of generated sequences"))
         .arg(Arg::with_name("partitions")
                  .short("p")
                  .long("partitions")
                  .takes_value(true)
-                 .help("File with coalescent tree partitions"))
+                 .required_unless("length")
+                 .help("File with the sequence length of each tree, one \
+                        per line in tree order, blank lines and # comments \
+                        are skipped. May be gzip compressed"))
+        .arg(Arg::with_name("model")
+                 .short("m")
+                 .long("model")
+                 .takes_value(true)
+                 .possible_values(&["hky", "jc69", "k80", "f81", "tn93",
+                                    "gtr", "codon", "poisson-aa", "jtt",
+                                    "wag", "lg"])
+                 .default_value("hky")
+                 .help("Substitution model"))
+        .arg(Arg::with_name("no-rate-normalization")
+                 .long("no-rate-normalization")
+                 .conflicts_with_all(&["models", "model-file"])
+                 .help("Don't normalize HKY's rate matrix to a mean rate of \
+                        1, branch lengths are then in raw rate matrix time \
+                        rather than expected substitutions per site"))
+        .arg(Arg::with_name("models")
+                 .long("models")
+                 .takes_value(true)
+                 .requires("partitions")
+                 .help("File with the substitution model of each partition, \
+                        one per line (overrides --model)"))
+        .arg(Arg::with_name("model-file")
+                 .long("model-file")
+                 .takes_value(true)
+                 .conflicts_with("models")
+                 .help("File with a custom 4x4 or 20x20 rate matrix, a row \
+                        per line, then a line of equilibrium frequencies \
+                        (overrides --model)"))
+        .arg(Arg::with_name("ancestral-fasta")
+                 .long("ancestral-fasta")
+                 .takes_value(true)
+                 .help("FASTA file with the root sequence of each \
+                        partition, in order, instead of random ones"))
+        .arg(Arg::with_name("shared-root")
+                 .long("shared-root")
+                 .conflicts_with_all(&["ancestral-fasta", "models"])
+                 .help("Draw a single random root sequence for the whole \
+                        alignment and give each partition its slice, \
+                        instead of a root per partition"))
+        .arg(Arg::with_name("rna")
+                 .long("rna")
+                 .help("Simulate RNA, using U instead of T (nucleotide \
+                        models only)"))
+        .arg(Arg::with_name("rates")
+                 .long("rates")
+                 .takes_value(true)
+                 .help("GTR exchangeability rates for AC,AG,AT,CG,CT,GT"))
+        .arg(Arg::with_name("freqs")
+                 .long("freqs")
+                 .takes_value(true)
+                 .help("HKY, F81, TN93, GTR and codon base frequencies \
+                        for A,G,C,T, must sum to 1"))
+        .arg(Arg::with_name("kappa")
+                 .long("kappa")
+                 .takes_value(true)
+                 .help("HKY, K80 and codon transition/transversion ratio"))
+        .arg(Arg::with_name("omega")
+                 .long("omega")
+                 .takes_value(true)
+                 .help("Codon model nonsynonymous/synonymous rate ratio \
+                        (dN/dS), lengths are then counted in codons"))
+        .arg(Arg::with_name("genetic-code")
+                 .long("genetic-code")
+                 .takes_value(true)
+                 .help("NCBI translation table used by the codon model: 1 \
+                        (standard, default) or 2 (vertebrate \
+                        mitochondrial)"))
+        .arg(Arg::with_name("kappa1")
+                 .long("kappa1")
+                 .takes_value(true)
+                 .help("TN93 purine (A <-> G) transition/transversion ratio"))
+        .arg(Arg::with_name("kappa2")
+                 .long("kappa2")
+                 .takes_value(true)
+                 .help("TN93 pyrimidine (C <-> T) transition/transversion \
+                        ratio"))
+        .arg(Arg::with_name("total-length")
+                 .long("total-length")
+                 .takes_value(true)
+                 .requires("partitions")
+                 .help("Expected sum of all partitions, mismatches are an \
+                        error"))
+        .arg(Arg::with_name("trees-subset")
+                 .long("trees-subset")
+                 .takes_value(true)
+                 .conflicts_with_all(&["ancestral-fasta",
+                     "rate-variation-file"])
+                 .help("Only simulate trees START-END (or a single tree N), \
+                        counting from 1. They get the same seeds as in a \
+                        run over the whole file."))
+        .arg(Arg::with_name("max-bases")
+                 .long("max-bases")
+                 .takes_value(true)
+                 .requires("partitions")
+                 .help("Maximum sum of all partitions, checked before \
+                        anything is allocated (unlimited by default)"))
         .arg(Arg::with_name("scale")
                  .short("s")
                  .long("scale")
                  .takes_value(true)
-                 .help("Branch scaling factor"))
+                 .help("Branch scaling factor, applied to substitutions \
+                        and indels"))
+        .arg(Arg::with_name("mutation-rate")
+                 .long("mutation-rate")
+                 .takes_value(true)
+                 .help("Substitutions per site per unit of scaled branch \
+                        length, for trees in generations or coalescent \
+                        units. Branches become branch * scale * rate \
+                        expected substitutions [default: 1]"))
+        .arg(Arg::with_name("branch-min")
+                 .long("branch-min")
+                 .takes_value(true)
+                 .help("Branches shorter than this, in tree units before \
+                        --scale, copy their parent's sequence instead of \
+                        mutating it [default: 0]"))
+        .arg(Arg::with_name("burnin-branch")
+                 .long("burnin-branch")
+                 .takes_value(true)
+                 .conflicts_with("ancestral-fasta")
+                 .help("Mutate each root sequence along a branch this long \
+                        before evolving its tree, so it starts closer to \
+                        the model's equilibrium frequencies"))
+        .arg(Arg::with_name("root-frequencies")
+                 .long("root-frequencies")
+                 .takes_value(true)
+                 .conflicts_with("ancestral-fasta")
+                 .help("Draw random root sequences from these frequencies \
+                        for A,G,C,T instead of the model's equilibrium \
+                        ones. Substitutions still follow the model."))
+        .arg(Arg::with_name("indel-rate")
+                 .long("indel-rate")
+                 .takes_value(true)
+                 .help("Insertions and deletions per site per unit of \
+                        branch length. Sequences then have different \
+                        lengths and the output is unaligned, so PHYLIP \
+                        and NEXUS can't be written [default: 0]"))
+        .arg(Arg::with_name("indel-length")
+                 .long("indel-length")
+                 .takes_value(true)
+                 .requires("indel-rate")
+                 .help("Mean length of indels, in sites [default: 2]"))
+        .arg(Arg::with_name("negative-branches")
+                 .long("negative-branches")
+                 .takes_value(true)
+                 .possible_values(&["error", "zero"])
+                 .default_value("error")
+                 .help("Whether negative branch lengths are an error or \
+                        set to 0"))
+        .arg(Arg::with_name("resolve-polytomies")
+                 .long("resolve-polytomies")
+                 .help("Split nodes with more than two children into \
+                        bifurcations joined by zero length branches"))
+        .arg(Arg::with_name("require-binary")
+                 .long("require-binary")
+                 .help("Fail unless every internal node has exactly two \
+                        children (after --resolve-polytomies)"))
+        .arg(Arg::with_name("require-ultrametric")
+                 .long("require-ultrametric")
+                 .help("Fail unless every tip of each tree is the same \
+                        distance from its root, as in coalescent trees"))
+        .arg(Arg::with_name("strict")
+                 .long("strict")
+                 .help("Fail if a tip's branch is zero (or shorter than \
+                        1e-8), as it would copy its parent's sequence"))
+        .arg(Arg::with_name("rescale-height")
+                 .long("rescale-height")
+                 .takes_value(true)
+                 .help("Scale each tree so its deepest tip is this far \
+                        from the root"))
+        .arg(Arg::with_name("gamma")
+                 .long("gamma")
+                 .takes_value(true)
+                 .help("Shape of the gamma distribution of per site rates"))
+        .arg(Arg::with_name("gamma-cats")
+                 .long("gamma-cats")
+                 .takes_value(true)
+                 .requires("gamma")
+                 .help("Use this many discrete gamma rate categories \
+                        instead of a continuous distribution"))
+        .arg(Arg::with_name("pinvar")
+                 .long("pinvar")
+                 .takes_value(true)
+                 .help("Proportion of invariant sites, in [0, 1)"))
+        .arg(Arg::with_name("rate-variation-file")
+                 .long("rate-variation-file")
+                 .takes_value(true)
+                 .conflicts_with_all(&["gamma", "pinvar"])
+                 .help("File with the rate of every site, one per line and \
+                        in partition order, used instead of drawing rates"))
+        .arg(Arg::with_name("ancestral")
+                 .long("ancestral")
+                 .help("Also output sequences of internal nodes, unlabeled \
+                        nodes are named node_N in preorder"))
+        .arg(Arg::with_name("output-ancestral-tree")
+                 .long("output-ancestral-tree")
+                 .takes_value(true)
+                 .help("Write the trees to this file as Newick, with \
+                        internal nodes labeled as in --ancestral output"))
+        .arg(Arg::with_name("auto-tip-ids")
+                 .long("auto-tip-ids")
+                 .help("Name unlabeled tips tip_N, N being their position \
+                        among tips in preorder"))
+        .arg(Arg::with_name("check")
+                 .long("check")
+                 .visible_alias("dry-run")
+                 .help("Validate the inputs and print a summary of them \
+                        without simulating"))
+        .arg(Arg::with_name("tree-stats")
+                 .long("tree-stats")
+                 .help("Print the tips, height and total branch length of \
+                        every tree before simulating"))
+        .arg(Arg::with_name("stats")
+                 .long("stats")
+                 .help("Print the composition of the simulated sequences \
+                        to stderr"))
+        .arg(Arg::with_name("divergence")
+                 .long("divergence")
+                 .requires("ancestral")
+                 .help("Print the differences between the root and every \
+                        other sequence to stderr"))
+        .arg(Arg::with_name("fill-missing")
+                 .long("fill-missing")
+                 .help("Fill the sites of taxa missing from some trees with \
+                        gaps (-)"))
+        .arg(Arg::with_name("charset-file")
+                 .long("charset-file")
+                 .takes_value(true)
+                 .conflicts_with_all(&["split-partitions", "indel-rate"])
+                 .help("Write RAxML/IQ-TREE partition definitions giving \
+                        the columns of each partition in the output"))
+        .arg(Arg::with_name("rates-out")
+                 .long("rates-out")
+                 .takes_value(true)
+                 .conflicts_with("indel-rate")
+                 .help("Write the rate each column of the output evolved at \
+                        under --gamma, --pinvar or --rate-variation-file \
+                        (1 otherwise) to this file. Replicate N goes to the \
+                        file with %d replaced by N, or to file.N"))
+        .arg(Arg::with_name("substitution-log")
+                 .long("substitution-log")
+                 .takes_value(true)
+                 .help("Write the length of every branch and the number of \
+                        sites that changed along it to this file, internal \
+                        nodes are named as in --ancestral output. Replicate \
+                        N goes to the file with %d replaced by N, or to \
+                        file.N"))
+        .arg(Arg::with_name("model-params-out")
+                 .long("model-params-out")
+                 .takes_value(true)
+                 .help("Write the seed, rate and indel settings and every \
+                        model's parameters and frequencies to this file, as \
+                        TOML"))
+        .arg(Arg::with_name("split-partitions")
+                 .long("split-partitions")
+                 .takes_value(true)
+                 .value_name("dir")
+                 .conflicts_with_all(&["outfile", "low-memory", "stats",
+                                       "divergence"])
+                 .help("Write each partition's sequences to its own file in \
+                        this directory, partition_N with the format's \
+                        extension, instead of concatenating them. \
+                        Replicate N goes in dir.N"))
+        .arg(Arg::with_name("variable-only")
+                 .long("variable-only")
+                 .conflicts_with_all(&["ancestral", "indel-rate", "low-memory",
+                     "split-partitions", "charset-file", "rates-out"])
+                 .help("Leave out sites where every tip has the same \
+                        symbols"))
+        .arg(Arg::with_name("low-memory")
+                 .long("low-memory")
+                 .conflicts_with_all(&["stats", "divergence", "fill-missing"])
+                 .help("Spool sequences to temporary files as trees are \
+                        simulated instead of holding the whole alignment \
+                        in memory, slower due to the extra disk I/O (fasta \
+                        and phylip-like formats only)"))
+        .arg(Arg::with_name("precision")
+                 .long("precision")
+                 .takes_value(true)
+                 .default_value("6")
+                 .help("Decimals of branch lengths in written trees and of \
+                        the numbers in reports"))
+        .arg(Arg::with_name("quiet")
+                 .short("q")
+                 .long("quiet")
+                 .conflicts_with("verbose")
+                 .help("Only print warnings and errors"))
+        .arg(Arg::with_name("verbose")
+                 .short("v")
+                 .long("verbose")
+                 .help("Print extra progress details"))
+        .arg(Arg::with_name("replicates")
+                 .long("replicates")
+                 .takes_value(true)
+                 .help("Number of independent datasets to simulate, \
+                        replicate N is written to the outfile with %d \
+                        replaced by N, or to outfile.N (before any .gz)"))
         .arg(Arg::with_name("threads")
                  .long("threads")
                  .takes_value(true)
-                 .help("Maximum number of threads to spawn"))
+                 .help("Maximum number of threads to spawn, 0 uses every \
+                        logical core and 1 runs single-threaded \
+                        [default: 0]"))
+        .arg(Arg::with_name("seed")
+                 .long("seed")
+                 .takes_value(true)
+                 .help("Seed for reproducible runs, the i-th tree in the \
+                        input (counting from 0) is simulated with seed + i, \
+                        plus r times the number of trees in replicate r. \
+                        Output doesn't depend on --threads"))
         .get_matches();
 
+    let log_level = if matches.is_present("quiet") {
+        LevelFilter::Warn
+    } else if matches.is_present("verbose") {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log_level);
+
     // Get args
     let tree_file = matches.value_of("treefile").unwrap();
-    let out_file  = matches.value_of("outfile").unwrap();
+    let out_file  = matches.value_of("outfile");
+    let format    = matches.value_of("format").unwrap();
+    let model     = matches.value_of("model").unwrap();
+    let ancestral = matches.is_present("ancestral");
+    let rna       = matches.is_present("rna");
+    let show_stats = matches.is_present("stats");
+    let show_divergence = matches.is_present("divergence");
+    let low_memory = matches.is_present("low-memory");
+    let variable_only = matches.is_present("variable-only");
+    let append = matches.is_present("append");
+    let interleaved = matches.is_present("phylip-interleaved");
+
+    let mut fasta_width = output::FASTA_WIDTH;
+    if let Some(width_arg) = matches.value_of("fasta-width") {
+        fasta_width = match width_arg.parse::<usize>() {
+            Ok(w) => w,
+            Err(_) => panic!("--fasta-width argument is not a positive \
+                integer")
+        }
+    }
+    let tree_order = matches.value_of("sort-taxa") == Some("tree-order");
+
+    let negative = match matches.value_of("negative-branches").unwrap() {
+        "zero" => NegativeBranches::Zero,
+        _      => NegativeBranches::Error
+    };
 
     let partition_fp: Option<&str> = matches.value_of("partitions");
+    if tree_file == "-" && partition_fp == Some("-") {
+        eprintln!("Trees and partitions can't both be read from stdin");
+        std::process::exit(1);
+    }
+    let models_fp: Option<&str> = matches.value_of("models");
+    let model_file: Option<&str> = matches.value_of("model-file");
 
-    let mut threads: usize = 1;
-    let threads_arg = matches.value_of("threads");
-    if threads_arg.is_some() {
-        threads = match threads_arg.unwrap().parse::<usize>() {
+    let mut length: Option<usize> = None;
+    if let Some(length_arg) = matches.value_of("length") {
+        length = match length_arg.parse::<usize>() {
+            Ok(l) => Some(l),
+            Err(_) => panic!("--length argument is not a positive integer")
+        }
+    }
+
+    let mut total_length: Option<usize> = None;
+    if let Some(total_arg) = matches.value_of("total-length") {
+        total_length = match total_arg.parse::<usize>() {
+            Ok(l) => Some(l),
+            Err(_) => panic!("--total-length argument is not a positive \
+                integer")
+        }
+    }
+
+    let mut max_bases: Option<usize> = None;
+    if let Some(max_arg) = matches.value_of("max-bases") {
+        max_bases = match max_arg.parse::<usize>() {
+            Ok(m) => Some(m),
+            Err(_) => panic!("--max-bases argument is not a positive integer")
+        }
+    }
+
+    let mut trees_subset: Option<RangeInclusive<usize>> = None;
+    if let Some(subset_arg) = matches.value_of("trees-subset") {
+        let (start, end) = subset_arg.split_once('-')
+            .unwrap_or((subset_arg, subset_arg));
+        trees_subset = match (start.trim().parse::<usize>(),
+            end.trim().parse::<usize>()) {
+            (Ok(a), Ok(b)) if a >= 1 && a <= b => Some(a..=b),
+            _ => panic!("--trees-subset argument is not a range START-END \
+                of trees counting from 1")
+        }
+    }
+
+    // rayon takes 0 threads as one per logical core
+    let mut threads: usize = 0;
+    if let Some(threads_arg) = matches.value_of("threads") {
+        threads = match threads_arg.parse::<usize>() {
             Ok(t) => t,
-            Err(_) => panic!("--threads argument is not a positive integer")
+            Err(_) => panic!("--threads argument is not a non-negative \
+                integer")
+        }
+    }
+
+    let mut replicates: usize = 1;
+    if let Some(replicates_arg) = matches.value_of("replicates") {
+        replicates = match replicates_arg.parse::<usize>() {
+            Ok(r) if r > 0 => r,
+            _ => panic!("--replicates argument is not a positive integer")
         }
     }
 
+    if replicates > 1 && out_file == Some("-") {
+        eprintln!("--replicates needs an output file, not stdout");
+        std::process::exit(1);
+    }
+
     let mut scale: f64 = 1.0;
-    let scale_arg = matches.value_of("scale");
-    if scale_arg.is_some() {
-        scale = match scale_arg.unwrap().parse::<f64>() {
+    if let Some(scale_arg) = matches.value_of("scale") {
+        scale = match scale_arg.parse::<f64>() {
             Ok(s) => s,
             Err(_) => panic!("--scale argument is not a float")
         }
     }
 
+    let mut mutation_rate: f64 = 1.0;
+    if let Some(rate_arg) = matches.value_of("mutation-rate") {
+        mutation_rate = match rate_arg.parse::<f64>() {
+            Ok(mu) if mu >= 0.0 => mu,
+            _ => panic!("--mutation-rate argument is not a non-negative float")
+        }
+    }
+
+    let mut kappa: f64 = 1.0;
+    if let Some(kappa_arg) = matches.value_of("kappa") {
+        kappa = match kappa_arg.parse::<f64>() {
+            Ok(k) => k,
+            Err(_) => panic!("--kappa argument is not a float")
+        }
+    }
+
+    let mut omega: f64 = 1.0;
+    if let Some(omega_arg) = matches.value_of("omega") {
+        omega = match omega_arg.parse::<f64>() {
+            Ok(o) => o,
+            Err(_) => panic!("--omega argument is not a float")
+        }
+    }
+
+    let mut genetic_code = mutator::GeneticCode::Standard;
+    if let Some(code_arg) = matches.value_of("genetic-code") {
+        genetic_code = match code_arg.parse::<usize>().ok()
+            .and_then(mutator::GeneticCode::from_id) {
+            Some(c) => c,
+            None    => panic!("--genetic-code must be 1 or 2")
+        }
+    }
+
+    let mut kappa1: f64 = 1.0;
+    if let Some(kappa1_arg) = matches.value_of("kappa1") {
+        kappa1 = match kappa1_arg.parse::<f64>() {
+            Ok(k) => k,
+            Err(_) => panic!("--kappa1 argument is not a float")
+        }
+    }
+
+    let mut kappa2: f64 = 1.0;
+    if let Some(kappa2_arg) = matches.value_of("kappa2") {
+        kappa2 = match kappa2_arg.parse::<f64>() {
+            Ok(k) => k,
+            Err(_) => panic!("--kappa2 argument is not a float")
+        }
+    }
+
+    let precision = match matches.value_of("precision").unwrap()
+        .parse::<usize>() {
+        Ok(p)  => p,
+        Err(_) => panic!("--precision argument is not a positive integer")
+    };
+
+    let mut branch_min: f64 = 0.0;
+    if let Some(min_arg) = matches.value_of("branch-min") {
+        branch_min = match min_arg.parse::<f64>() {
+            Ok(b) if b >= 0.0 => b,
+            _ => panic!("--branch-min argument is not a non-negative float")
+        }
+    }
+
+    let mut burnin: f64 = 0.0;
+    if let Some(burnin_arg) = matches.value_of("burnin-branch") {
+        burnin = match burnin_arg.parse::<f64>() {
+            Ok(b) if b >= 0.0 => b,
+            _ => panic!("--burnin-branch argument is not a non-negative \
+                float")
+        }
+    }
+
+    let mut root_frequencies: Option<Vec<f64>> = None;
+    if let Some(freqs_arg) = matches.value_of("root-frequencies") {
+        let mut root_freqs: [f64; 4] = [0.25; 4];
+        parse_float_list(freqs_arg, "--root-frequencies", &mut root_freqs);
+        root_frequencies = match mutator::check_frequencies(root_freqs) {
+            Ok(f)  => Some(f.to_vec()),
+            Err(e) => panic!("Invalid --root-frequencies: {}", e)
+        };
+    }
+
+    let mut indels: Option<IndelModel> = None;
+    if let Some(rate_arg) = matches.value_of("indel-rate") {
+        let rate = match rate_arg.parse::<f64>() {
+            Ok(r)  => r,
+            Err(_) => panic!("--indel-rate argument is not a float")
+        };
+        let length = match matches.value_of("indel-length").unwrap_or("2")
+            .parse::<f64>() {
+            Ok(l)  => l,
+            Err(_) => panic!("--indel-length argument is not a float")
+        };
+
+        // Branches are scaled for indels as they are for substitutions
+        indels = match IndelModel::new(rate * scale, length) {
+            Ok(m)  => Some(m),
+            Err(e) => panic!("Couldn't create indel model: {}", e)
+        };
+    }
+
+    let mut rescale_height: Option<f64> = None;
+    if let Some(height_arg) = matches.value_of("rescale-height") {
+        rescale_height = match height_arg.parse::<f64>() {
+            Ok(h) => Some(h),
+            Err(_) => panic!("--rescale-height argument is not a float")
+        }
+    }
+
+    let mut gamma_cats: Option<usize> = None;
+    if let Some(cats_arg) = matches.value_of("gamma-cats") {
+        gamma_cats = match cats_arg.parse::<usize>() {
+            Ok(c) => Some(c),
+            Err(_) => panic!("--gamma-cats argument is not a positive integer")
+        }
+    }
+
+    let mut site_rates = match matches.value_of("gamma") {
+        Some(gamma_arg) => {
+            let alpha = match gamma_arg.parse::<f64>() {
+                Ok(a) => a,
+                Err(_) => panic!("--gamma argument is not a float")
+            };
+
+            match rates::RateHeterogeneity::gamma(alpha, gamma_cats) {
+                Ok(r)  => r,
+                Err(e) => panic!("Invalid rate heterogeneity: {}", e)
+            }
+        },
+        None => rates::RateHeterogeneity::uniform()
+    };
+
+    if let Some(pinvar_arg) = matches.value_of("pinvar") {
+        let pinvar = match pinvar_arg.parse::<f64>() {
+            Ok(p) => p,
+            Err(_) => panic!("--pinvar argument is not a float")
+        };
+
+        site_rates = match site_rates.with_pinvar(pinvar) {
+            Ok(r)  => r,
+            Err(e) => panic!("Invalid rate heterogeneity: {}", e)
+        };
+    }
+
+    let seed: u64 = match matches.value_of("seed") {
+        Some(seed_arg) => match seed_arg.parse::<u64>() {
+            Ok(s) => s,
+            Err(_) => panic!("--seed argument is not a positive integer")
+        },
+        None => rand::random::<u64>()
+    };
+    debug!("Using seed {}", seed);
+
+    let mut rates: [f64; 6] = [1.0; 6];
+    if let Some(rates_arg) = matches.value_of("rates") {
+        parse_float_list(rates_arg, "--rates", &mut rates);
+    }
+
+    let mut freqs: [f64; 4] = [0.25; 4];
+    if let Some(freqs_arg) = matches.value_of("freqs") {
+        parse_float_list(freqs_arg, "--freqs", &mut freqs);
+    }
+
+    // Other formats need every sequence's length or name before writing
+    // any of them, so they can't be written one taxon at a time
+    if low_memory && !["fasta", "phylip-like"].contains(&format) {
+        eprintln!("--low-memory only supports the fasta and phylip-like \
+            formats, not {}", format);
+        std::process::exit(1);
+    }
+
+    // Sequences with indels are unaligned, so formats holding a matrix of
+    // sites can't be written
+    if matches.is_present("indel-rate") &&
+        !["fasta", "phylip-like"].contains(&format) {
+        eprintln!("--indel-rate output is unaligned, only the fasta and \
+            phylip-like formats can hold it, not {}", format);
+        std::process::exit(1);
+    }
+
+    // Nucleotide models emit their fourth base as is, so RNA only needs a U
+    let bases: [u8; 4] = if rna { *b"AGCU" } else { *b"AGCT" };
+    if rna && ["poisson-aa", "jtt", "wag", "lg"].contains(&model) {
+        panic!("--rna can't be used with amino acid model {}", model);
+    }
+
+    let unnormalized = matches.is_present("no-rate-normalization");
+    if unnormalized && model != "hky" {
+        eprintln!("--no-rate-normalization only applies to the hky model, \
+            not {}", model);
+        std::process::exit(1);
+    }
+
     // Initialize multithreading env
     ThreadPoolBuilder::new().num_threads(threads).build_global().unwrap();
 
+    // Models get expected substitutions per site as
+    // scaled_v = branch * scale * mu
+    let scale = scale * mutation_rate;
+
+    // Create a mutator model
+    match (models_fp, model_file) {
+        (Some(fp), _)    => debug!("Using the models in {}", fp),
+        (None, Some(fp)) => debug!("Using the rate matrix in {}", fp),
+        (None, None)     => debug!("Using model {}", model)
+    }
+    let mut_model: Box<dyn Mutator> = match model {
+        _ if model_file.is_some() => match parsers::parse_rate_matrix(
+            model_file.unwrap(), bases, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => {
+                eprintln!("Model file error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        "jc69" => Box::new(mutator::JC69::new(
+            bases[0], bases[1], bases[2], bases[3], scale)),
+        "k80"  => match mutator::K80::new(bases[0], bases[1], bases[2],
+            bases[3], kappa, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("K80", &e)
+        },
+        "f81"  => match mutator::F81::new(freqs, bases, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("F81", &e)
+        },
+        "codon" => match mutator::Codon::with_code(freqs, bases, kappa, omega,
+            genetic_code, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("codon", &e)
+        },
+        "poisson-aa" => Box::new(mutator::PoissonAA::new(scale)),
+        "jtt" | "wag" | "lg" => {
+            let aa_model = match model {
+                "jtt" => aa_models::AAModel::JTT,
+                "wag" => aa_models::AAModel::WAG,
+                _     => aa_models::AAModel::LG
+            };
+
+            match mutator::EmpiricalAA::new(aa_model, scale) {
+                Ok(m)  => Box::new(m),
+                Err(e) => model_error(model, &e)
+            }
+        },
+        "tn93" => match mutator::TN93::new(freqs, bases, kappa1, kappa2,
+            scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("TN93", &e)
+        },
+        "gtr"  => match mutator::GTR::new(rates, freqs, bases, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("GTR", &e)
+        },
+        _ if unnormalized => match mutator::HKY::new_unnormalized(freqs[0],
+            freqs[1], freqs[2], freqs[3], bases[0], bases[1], bases[2],
+            bases[3], kappa, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("HKY", &e)
+        },
+        _      => match mutator::HKY::new(freqs[0], freqs[1], freqs[2],
+            freqs[3], bases[0], bases[1], bases[2], bases[3], kappa, scale) {
+            Ok(m)  => Box::new(m),
+            Err(e) => model_error("HKY", &e)
+        }
+    };
+
+    let datatype = if mut_model.alphabet() == mutator::AMINO_ACIDS {
+        output::DataType::Protein
+    } else if rna {
+        output::DataType::RNA
+    } else {
+        output::DataType::DNA
+    };
+
+    // Every tree evolves under --model unless --models gives one per tree
+    let mut part_models: Vec<Box<dyn Mutator>> = match models_fp {
+        Some(fp) => match parsers::parse_models(fp, bases, scale) {
+            Ok(m)  => m,
+            Err(e) => {
+                eprintln!("Model parse error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new()
+    };
+
     // Parse coalescent tree inputs
-    let parse_res = match partition_fp {
-        Some(p) => parsers::parse_newick_partitioned(tree_file, p),
-        None    => panic!("--length arg not implemented yet! Try --partitions")
+    // (clap guarantees exactly one of --partitions or --length is given)
+    let parse_res = match (partition_fp, length) {
+        (Some(p), _)    => parsers::parse_newick_partitioned(tree_file, p,
+            total_length, max_bases, trees_subset.clone(), negative),
+        (None, Some(l)) => parsers::parse_newick_single(tree_file, l,
+            trees_subset.clone(), negative),
+        (None, None)    => unreachable!()
     };
 
-    let mut tree_vec = match parse_res {
+    // Trees in the input, which may be more than the subset kept
+    let (mut tree_vec, n_input_trees) = match parse_res {
         Ok(t)  => t,
-        Err(x) => panic!("Parse error: {}", x)
+        Err(x) => {
+            eprintln!("Parse error: {}", x);
+            std::process::exit(1);
+        }
     };
 
-    println!("Done parsing trees");
+    info!("Done parsing trees");
 
-    // Create a mutator model
-    let mut_model = mutator::HKY::new(0.25, 0.25, 0.25, 0.25,
-        'A' as u8, 'G' as u8, 'C' as u8, 'T' as u8, 1.0, scale);
-
-    // Create ancestral sequences
-    println!("Building ancestrals...");
-    tree_vec.par_iter_mut().for_each(|t| t.create_ancestral(&mut_model));
-
-    // Evolve all trees
-    println!("Mutating ancestrals...");
-    let mut mutated_seqs =
-        vec![HashMap::<String, Sequence>::new(); tree_vec.len()];
-    tree_vec.par_iter_mut().zip(mutated_seqs.par_iter_mut()).for_each(
-        |(t, h)| t.dfs_evolve(&mut_model, h));
-    tree_vec.clear();
-
-    // Assemble mutant partitions
-    println!("Assembling mutants...");
-    let mut assembled_seqs = HashMap::<String, String>::new();
-    for h in mutated_seqs {
-        for (k, v) in h {
-            let k_o = assembled_seqs.get_mut(&k);
-            // If id exists in assembled sequences, append it
-            if k_o.is_some() {
-                k_o.unwrap().push_str(v.to_string())
-            // If we haven't touched this id, add a new pair
-            } else {
-                assembled_seqs.insert(k, String::from(v.to_string())); ()
-            }
-        }
-    }
-
-    // Print out our mutants
-    println!("Writing sequences...");
-    let mut out = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(out_file)
-        .unwrap();
-
-    for (k, v) in assembled_seqs {
-        if let Err(e) = writeln!(out, "{} {}", k, v) {
+    if let Some(height) = rescale_height {
+        for (i, tree) in tree_vec.iter_mut().enumerate() {
+            if let Err(e) = tree.rescale_to_height(height) {
+                panic!("Couldn't rescale tree {}: {}", i + 1, e);
+            }
+        }
+    }
+
+    if matches.is_present("tree-stats") {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            eprintln!("Tree {}: {} tips, {} bases, height {:.*}, total \
+                branch length {:.*}", i + 1, tree.n_tips(),
+                tree.get_partition(), precision, tree.height(), precision,
+                tree.total_length());
+        }
+    }
+
+    // The models file covers every tree, keep those of the subset
+    if let Some(r) = &trees_subset {
+        if part_models.len() >= *r.end() {
+            part_models.truncate(*r.end());
+            part_models.drain(..r.start() - 1);
+        }
+    }
+
+    if models_fp.is_some() && part_models.len() != tree_vec.len() {
+        eprintln!("Model parse error: {} models given for {} partitions",
+            part_models.len(), tree_vec.len());
+        std::process::exit(1);
+    }
+
+    let model_refs: Vec<&dyn Mutator> = if models_fp.is_some() {
+        part_models.iter().map(|m| &**m).collect()
+    } else {
+        vec![&*mut_model; tree_vec.len()]
+    };
+
+    // Sites are only whole across partitions if they all have the same
+    // width, otherwise invariant columns are removed one by one
+    let site_width = match model_refs.first() {
+        Some(m) if model_refs.iter()
+            .all(|n| n.site_width() == m.site_width()) => m.site_width(),
+        _ => 1
+    };
+
+    // Root frequencies are given per nucleotide
+    if root_frequencies.is_some() && model_refs.iter()
+        .any(|m| m.site_width() != 1 || m.alphabet().len() != 4) {
+        eprintln!("--root-frequencies only applies to nucleotide models");
+        std::process::exit(1);
+    }
+
+    let mut opts = SimulationOptions::new(seed);
+    opts.site_rates = site_rates;
+    opts.ancestral = ancestral;
+    opts.auto_tip_ids = matches.is_present("auto-tip-ids");
+    opts.branch_min = branch_min;
+    opts.burnin = burnin;
+    opts.first_tree = trees_subset.map_or(0, |r| r.start() - 1);
+    opts.root_frequencies = root_frequencies;
+    opts.fill_missing = matches.is_present("fill-missing");
+    opts.indels = indels;
+    opts.progress = !matches.is_present("quiet");
+    opts.shared_root = matches.is_present("shared-root");
+    opts.count_substitutions = matches.is_present("substitution-log");
+
+    if let Some(fp) = matches.value_of("rate-variation-file") {
+        opts.tree_site_rates = match parsers::parse_site_rates(fp, &tree_vec) {
+            Ok(r)  => Some(r),
+            Err(e) => {
+                eprintln!("Site rates error: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    if let Some(params_out) = matches.value_of("model-params-out") {
+        // A models file gives each partition its own model, otherwise they
+        // all share the same one
+        let models = if models_fp.is_some() {
+            &model_refs[..]
+        } else {
+            &model_refs[..model_refs.len().min(1)]
+        };
+        write_model_params(params_out, &opts, replicates,
+            matches.value_of("rate-variation-file"), models);
+    }
+
+    // Root sequences are matched to partitions by their order in the file
+    if let Some(fp) = matches.value_of("ancestral-fasta") {
+        let records = match parsers::parse_fasta(fp) {
+            Ok(r)  => r,
+            Err(e) => {
+                eprintln!("Ancestral FASTA parse error: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if records.len() != tree_vec.len() {
+            eprintln!("Ancestral FASTA error: {} sequences given for {} \
+                partitions", records.len(), tree_vec.len());
+            std::process::exit(1);
+        }
+
+        let mut roots = Vec::<Sequence>::new();
+        for (i, (id, seq)) in records.iter().enumerate() {
+            let part = tree_vec[i].get_partition() *
+                model_refs[i].site_width();
+            if seq.len() != part {
+                eprintln!("Ancestral FASTA error: sequence '{}' has {} \
+                    symbols, partition {} needs {}", id, seq.len(), i + 1,
+                    part);
+                std::process::exit(1);
+            }
+
+            match Sequence::from_ascii(seq.as_bytes(),
+                model_refs[i].alphabet()) {
+                Ok(s)  => roots.push(s),
+                Err(e) => {
+                    eprintln!("Ancestral FASTA error: sequence '{}': {}",
+                        id, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        opts.root_sequences = Some(roots);
+    }
+
+    if matches.is_present("resolve-polytomies") {
+        for (i, tree) in tree_vec.iter_mut().enumerate() {
+            let resolved = tree.resolve_polytomies();
+            if resolved > 0 {
+                debug!("Resolved {} polytomies in tree {}", resolved, i + 1);
+            }
+        }
+    }
+
+    if matches.is_present("require-binary") {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            let non_binary = tree.non_binary_nodes();
+            if non_binary > 0 {
+                eprintln!("Tree {} has {} nodes without exactly two \
+                    children, but --require-binary was given", i + 1,
+                    non_binary);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("require-ultrametric") {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            let height = tree.height();
+            if !tree.is_ultrametric(ULTRAMETRIC_TOLERANCE * height) {
+                eprintln!("Tree {} isn't ultrametric, but \
+                    --require-ultrametric was given", i + 1);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("strict") {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            let short = tree.short_tip_branches(STRICT_TIP_BRANCH);
+            if short > 0 {
+                eprintln!("Tree {} has {} tips with zero length branches, \
+                    which --strict doesn't allow", i + 1, short);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Tips need ids to be output, unless they're given automatic ones
+    if !opts.auto_tip_ids {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            let unnamed = tree.unnamed_tips();
+            if unnamed > 0 {
+                eprintln!("Tree {} has {} unnamed tips, name them or use \
+                    --auto-tip-ids", i + 1, unnamed);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Internal nodes are output by id too, so ids must be unique in a tree
+    if ancestral {
+        for (i, tree) in tree_vec.iter().enumerate() {
+            if let Some(id) = tree.repeated_id() {
+                eprintln!("Tree {} has more than one node labeled '{}', \
+                    their ancestral sequences can't both be output", i + 1,
+                    id);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("check") {
+        if opts.auto_tip_ids {
+            tree_vec.iter_mut().for_each(|t| t.label_tips());
+        }
+
+        let mut taxa: Vec<String> = tree_vec.iter()
+            .flat_map(|t| t.output_ids(false)).collect();
+        taxa.sort();
+        taxa.dedup();
+
+        let bases: usize = tree_vec.iter().map(|t| t.get_partition()).sum();
+        eprintln!("Inputs are valid: {} trees, {} sites in total, {} taxa",
+            tree_vec.len(), bases, taxa.len());
+        return;
+    }
+
+    // Partitions are concatenated in input order, so their columns are the
+    // same for every replicate
+    if let Some(charset_file) = matches.value_of("charset-file") {
+        let partitions: Vec<(&str, usize)> = tree_vec.iter().zip(&model_refs)
+            .map(|(t, m)| {
+                let datatype = if m.alphabet() == mutator::AMINO_ACIDS {
+                    "PROT"
+                } else {
+                    "DNA"
+                };
+                (datatype, t.get_partition() * m.site_width())
+            })
+            .collect();
+
+        let write_res = output::OutputFile::create(charset_file)
+            .and_then(|mut out| {
+                output::write_charsets(&mut out, &partitions)?;
+                out.finish()
+            });
+
+        if let Err(e) = write_res {
+            panic!("Couldn't write partition definitions: {}", e);
+        }
+    }
+
+    // Replicates use consecutive blocks of seeds, so no two trees in any
+    // replicate share one and replicate 1 matches a run without replicates.
+    // Blocks span every input tree, so subsets keep the seeds they'd have
+    // in a run over the whole input.
+    let n_trees = n_input_trees as u64;
+    let rates_out = matches.value_of("rates-out");
+    let substitution_log = matches.value_of("substitution-log");
+    for r in 0..replicates {
+        opts.seed = seed.wrapping_add(r as u64 * n_trees);
+        if let Some(dir) = matches.value_of("split-partitions") {
+            let dir = replicate_path(dir, r + 1, replicates);
+            if replicates > 1 {
+                info!("Simulating replicate {} into {}", r + 1, dir);
+            }
+
+            simulate_split(&mut tree_vec, &model_refs, &opts, &dir, format,
+                interleaved, datatype, fasta_width, tree_order);
+            write_rates(rates_out, r + 1, replicates, &tree_vec, &model_refs,
+                precision);
+            write_substitutions(substitution_log, r + 1, replicates,
+                &tree_vec, precision);
+            continue;
+        }
+
+        let path = replicate_path(out_file.unwrap(), r + 1, replicates);
+        if replicates > 1 {
+            info!("Simulating replicate {} into {}", r + 1, path);
+        }
+
+        if low_memory {
+            simulate_low_memory(&mut tree_vec, &model_refs, &opts, &path,
+                format, fasta_width, tree_order, append);
+            write_rates(rates_out, r + 1, replicates, &tree_vec, &model_refs,
+                precision);
+            write_substitutions(substitution_log, r + 1, replicates,
+                &tree_vec, precision);
+            continue;
+        }
+
+        let mut assembled_seqs = match aminosim::simulate_partitioned(
+            &mut tree_vec, &model_refs, &opts) {
+            Ok(s)  => s,
+            Err(e) => {
+                eprintln!("Simulation error: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut assembled_seqs: Vec<(String, String)> = if tree_order {
+            aminosim::taxon_order(&tree_vec, &opts).into_iter()
+                .map(|id| {
+                    let seq = assembled_seqs.remove(&id).unwrap();
+                    (id, seq)
+                })
+                .collect()
+        } else {
+            assembled_seqs.into_iter().collect()
+        };
+
+        if variable_only {
+            let removed = aminosim::remove_invariant_sites(
+                &mut assembled_seqs, site_width);
+            info!("Removed {} invariant sites", removed);
+        }
+
+        write_rates(rates_out, r + 1, replicates, &tree_vec, &model_refs,
+            precision);
+        write_substitutions(substitution_log, r + 1, replicates, &tree_vec,
+            precision);
+
+        // Print out our mutants
+        info!("Writing sequences...");
+        if let Err(e) = write_alignment(&path, &assembled_seqs, format,
+            interleaved, datatype, fasta_width, append) {
             panic!("Couldn't write to file: {}", e);
         }
+
+        if show_stats {
+            if let Err(e) = stats::write_report(&mut std::io::stderr(),
+                &assembled_seqs, precision) {
+                panic!("Couldn't write composition report: {}", e);
+            }
+        }
+
+        if show_divergence {
+            // Roots were labeled by the simulation, unless they had ids
+            let root = tree_vec[0].root_id().unwrap();
+            if tree_vec.iter().any(|t| t.root_id() != Some(root)) {
+                eprintln!("--divergence needs every tree's root to have the \
+                    same id");
+                std::process::exit(1);
+            }
+
+            if let Err(e) = stats::write_divergence(&mut std::io::stderr(),
+                &assembled_seqs, root, precision) {
+                panic!("Couldn't write divergence report: {}", e);
+            }
+        }
+    }
+
+    if let Some(tree_out) = matches.value_of("output-ancestral-tree") {
+        info!("Writing labeled trees...");
+        let write_res = output::OutputFile::create(tree_out)
+            .and_then(|mut out| {
+                for tree in tree_vec.iter_mut() {
+                    tree.label_internal_nodes();
+                    writeln!(out, "{}",
+                        tree.to_newick_with_precision(precision))?;
+                }
+                out.finish()
+            });
+
+        if let Err(e) = write_res {
+            panic!("Couldn't write trees: {}", e);
+        }
+    }
+
+    info!("All done!");
+}
+
+// Write the site rates 'trees' were last simulated with to the path of
+// replicate 'r' of 'rates_out', if given
+fn write_rates(rates_out: Option<&str>, r: usize, replicates: usize,
+    trees: &[aminosim::tree::NTree], models: &[&dyn Mutator],
+    precision: usize) {
+    let path = match rates_out {
+        Some(p) => replicate_path(p, r, replicates),
+        None    => return
+    };
+
+    let partitions: Vec<(usize, Option<&[f64]>)> = trees.iter().zip(models)
+        .map(|(t, m)| (t.get_partition() * m.site_width(), t.site_rates()))
+        .collect();
+
+    let write_res = output::OutputFile::create(&path).and_then(|mut out| {
+        output::write_site_rates(&mut out, &partitions, precision)?;
+        out.finish()
+    });
+
+    if let Err(e) = write_res {
+        panic!("Couldn't write site rates: {}", e);
+    }
+}
+
+// Write the substitutions along every branch of 'trees' in replicate 'r' to
+// 'log_out', if given
+fn write_substitutions(log_out: Option<&str>, r: usize, replicates: usize,
+    trees: &[aminosim::tree::NTree], precision: usize) {
+    let path = match log_out {
+        Some(p) => replicate_path(p, r, replicates),
+        None    => return
+    };
+
+    let branches: Vec<_> = trees.iter().map(|t| t.branch_substitutions())
+        .collect();
+
+    let write_res = output::OutputFile::create(&path).and_then(|mut out| {
+        output::write_substitution_log(&mut out, &branches, precision)?;
+        out.finish()
+    });
+
+    if let Err(e) = write_res {
+        panic!("Couldn't write substitution log: {}", e);
+    }
+}
+
+// Write the settings of the run and 'models' to 'path'
+fn write_model_params(path: &str, opts: &SimulationOptions,
+    replicates: usize, rates_file: Option<&str>, models: &[&dyn Mutator]) {
+    let mut settings: Vec<(&str, String)> = vec![
+        ("seed", opts.seed.to_string()),
+        ("replicates", replicates.to_string()),
+        ("branch_min", opts.branch_min.to_string()),
+        ("burnin", opts.burnin.to_string())
+    ];
+    if let Some(freqs) = &opts.root_frequencies {
+        settings.push(("root_frequencies", format!("{:?}", freqs)));
+    }
+    let mut parameters = opts.site_rates.parameters();
+    if let Some(indels) = &opts.indels {
+        parameters.extend(indels.parameters());
+    }
+    settings.extend(parameters.into_iter().map(|(k, v)| (k, v.to_string())));
+    if let Some(fp) = rates_file {
+        settings.push(("rate_variation_file", format!("{:?}", fp)));
+    }
+
+    let write_res = output::OutputFile::create(path).and_then(|mut out| {
+        output::write_model_params(&mut out, &settings, models)?;
+        out.finish()
+    });
+
+    if let Err(e) = write_res {
+        panic!("Couldn't write model parameters: {}", e);
+    }
+}
+
+// Write 'seqs' to 'path' in 'format', after what it holds if 'append'
+fn write_alignment(path: &str, seqs: &[(String, String)], format: &str,
+    interleaved: bool, datatype: output::DataType, fasta_width: usize,
+    append: bool) -> std::io::Result<()> {
+    let mut out = open_output(path, append)?;
+    match format {
+        "fasta"  => output::write_fasta(&mut out, seqs, fasta_width),
+        "phylip" => output::write_phylip(&mut out, seqs, interleaved),
+        "nexus"  => output::write_nexus(&mut out, seqs, datatype),
+        _        => output::write_phylip_like(&mut out, seqs)
+    }?;
+    out.finish()
+}
+
+// Simulate 'trees', writing the sequences of the i-th one to
+// 'dir'/partition_i with the extension of 'format' instead of
+// concatenating them
+#[allow(clippy::too_many_arguments)]
+fn simulate_split(trees: &mut [aminosim::tree::NTree],
+    models: &[&dyn Mutator], opts: &SimulationOptions, dir: &str,
+    format: &str, interleaved: bool, datatype: output::DataType,
+    fasta_width: usize, tree_order: bool) {
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        panic!("Couldn't create directory {}: {}", dir, e);
+    }
+
+    // Trees are only labeled once simulated, so their taxa are sorted after
+    let mut partitions = Vec::<HashMap<String, Sequence>>::new();
+    let batch = rayon::current_num_threads();
+    let sim_res = aminosim::simulate_streaming(trees, models, opts, batch,
+        |seqs| {
+            partitions.push(seqs);
+            Ok(())
+        });
+
+    if let Err(e) = sim_res {
+        eprintln!("Simulation error: {}", e);
+        std::process::exit(1);
+    }
+
+    let extension = match format {
+        "fasta"  => "fasta",
+        "phylip" => "phy",
+        "nexus"  => "nex",
+        _        => "txt"
+    };
+
+    info!("Writing {} partitions to {}...", partitions.len(), dir);
+    for (i, (mut seqs, tree)) in partitions.into_iter().zip(trees.iter())
+        .enumerate() {
+        let seqs: Vec<(String, String)> = if tree_order {
+            tree.output_ids(opts.ancestral).into_iter()
+                .map(|id| {
+                    let seq = seqs.remove(&id).unwrap();
+                    (id, seq.as_str().to_string())
+                })
+                .collect()
+        } else {
+            let mut seqs: Vec<(String, String)> = seqs.into_iter()
+                .map(|(k, v)| (k, v.as_str().to_string()))
+                .collect();
+            seqs.sort();
+            seqs
+        };
+
+        let path = Path::new(dir).join(format!("partition_{}.{}", i,
+            extension));
+        if let Err(e) = write_alignment(&path.to_string_lossy(), &seqs,
+            format, interleaved, datatype, fasta_width, false) {
+            panic!("Couldn't write {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Output file at 'path', appended to if 'append' or else replaced
+fn open_output(path: &str, append: bool) ->
+    std::io::Result<output::OutputFile> {
+    if append {
+        output::OutputFile::append(path)
+    } else {
+        output::OutputFile::create(path)
     }
+}
+
+// Output path of replicate 'r' (1-based) out of 'replicates': '%d' in
+// 'out_file' is replaced by 'r', or else ".r" is appended, ahead of a ".gz"
+// extension so the replicate is still compressed. Without '%d' a single
+// replicate is written to 'out_file' as is.
+fn replicate_path(out_file: &str, r: usize, replicates: usize) -> String {
+    if out_file.contains("%d") {
+        out_file.replace("%d", &r.to_string())
+    } else if replicates == 1 {
+        out_file.to_string()
+    } else if let Some(stem) = out_file.strip_suffix(".gz") {
+        format!("{}.{}.gz", stem, r)
+    } else {
+        format!("{}.{}", out_file, r)
+    }
+}
+
+// Simulate a tree batch at a time, appending each taxon's sequences to its
+// own temporary file, then write the taxa one by one. Peak memory is then
+// about a batch of trees' sequences plus one taxon's full sequence, rather
+// than the whole alignment, at the cost of writing everything to disk twice.
+#[allow(clippy::too_many_arguments)]
+fn simulate_low_memory(trees: &mut [aminosim::tree::NTree],
+    models: &[&dyn Mutator], opts: &SimulationOptions, out_file: &str,
+    format: &str, fasta_width: usize, tree_order: bool, append: bool) {
+    let mut spool = match output::TaxonSpool::new() {
+        Ok(s)  => s,
+        Err(e) => panic!("Couldn't create temporary files: {}", e)
+    };
+
+    // Batches as large as the thread pool keep every thread busy
+    let batch = rayon::current_num_threads();
+    let sim_res = aminosim::simulate_streaming(trees, models, opts, batch,
+        |seqs| {
+            for (k, v) in seqs {
+                spool.append(&k, v.as_str())?;
+            }
+
+            Ok(())
+        });
 
-    println!("All done!");
+    if let Err(e) = sim_res {
+        // Exiting skips destructors, clean up the temporary files first
+        drop(spool);
+        eprintln!("Simulation error: {}", e);
+        std::process::exit(1);
+    }
+
+    info!("Writing sequences...");
+    let mut out = match open_output(out_file, append) {
+        Ok(f)  => f,
+        Err(e) => panic!("Couldn't open output file: {}", e)
+    };
+
+    let ids: Vec<String> = if tree_order {
+        aminosim::taxon_order(trees, opts)
+    } else {
+        spool.ids().map(String::from).collect()
+    };
+
+    let bar = opts.progress
+        .then(|| aminosim::progress_bar("Writing", ids.len()));
+    let write_res = ids.iter().try_for_each(|id| {
+        let seq = spool.read(id)?;
+        if let Some(bar) = &bar {
+            bar.inc(1);
+        }
+        match format {
+            "fasta" => output::write_fasta_record(&mut out, id, &seq,
+                fasta_width),
+            _       => output::write_phylip_like_record(&mut out, id, &seq)
+        }
+    }).and_then(|_| out.finish());
+    if let Some(bar) = bar {
+        bar.finish();
+    }
+
+    if let Err(e) = write_res {
+        panic!("Couldn't write to file: {}", e);
+    }
+}
+
+// Report parameters a model rejected, like any other input error
+fn model_error(model: &str, e: &str) -> ! {
+    eprintln!("Invalid {} parameters: {}", model, e);
+    std::process::exit(1);
+}
+
+// Fill 'dest' from a comma separated list of floats, panicking with the
+// argument's name if the list is malformed or has the wrong length
+fn parse_float_list(arg: &str, name: &str, dest: &mut [f64]) {
+    let values: Vec<&str> = arg.split(',').collect();
+    if values.len() != dest.len() {
+        panic!("{} argument needs {} comma separated values, got {}",
+            name, dest.len(), values.len());
+    }
+
+    for (d, v) in dest.iter_mut().zip(values) {
+        *d = match v.trim().parse::<f64>() {
+            Ok(f) => f,
+            Err(_) => panic!("{} argument '{}' is not a float", name, v)
+        }
+    }
 }
@@ -1,43 +1,154 @@
 use crate::sequence::Sequence;
+use crate::aa_models::AAModel;
 
-use ndarray::arr2;
+use ndarray::{arr2, Array2};
 
+use std::collections::HashMap;
 use std::f64::consts::E;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rand::rngs::StdRng;
 use rand::distributions::{Uniform, Distribution};
 
-pub trait Mutator {
-    fn mutate(&self, s: &Sequence, v: f64) -> Sequence;
-    fn random(&self, l: usize) -> Sequence;
+// Why a Sequence couldn't be mutated, positions count symbols from 0
+#[derive(Clone, Debug, PartialEq)]
+pub enum MutationError {
+    // A symbol that isn't in the model's alphabet
+    UnknownSymbol { symbol: u8, position: usize },
+    // A stop codon, which codon models have no state for
+    StopCodon { codon: String, position: usize },
+    // A sequence without site classes given to a mixture model
+    MissingSiteClasses
 }
 
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MutationError::UnknownSymbol { symbol, position } => write!(f,
+                "Unrecognized symbol '{}' at position {} of a sequence \
+                being mutated", symbol.escape_ascii(), position + 1),
+            MutationError::StopCodon { codon, position } => write!(f,
+                "Stop codon {} at position {} of a sequence being mutated",
+                codon, position + 1),
+            MutationError::MissingSiteClasses => write!(f,
+                "Sequence being mutated by a mixture model has no site \
+                classes, it should come from 'random' or 'prepare_root'")
+        }
+    }
+}
+
+impl std::error::Error for MutationError {}
+
+impl MutationError {
+    // Same error in a sequence whose i-th symbol was at 'positions[i]'
+    fn remap(self, positions: &[usize]) -> MutationError {
+        match self {
+            MutationError::UnknownSymbol { symbol, position } =>
+                MutationError::UnknownSymbol { symbol,
+                    position: positions[position] },
+            MutationError::StopCodon { codon, position } =>
+                MutationError::StopCodon { codon,
+                    position: positions[position] },
+            MutationError::MissingSiteClasses =>
+                MutationError::MissingSiteClasses
+        }
+    }
+}
+
+pub trait Mutator: Sync {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError>;
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence;
+    // Symbols the model's sequences are made of
+    fn alphabet(&self) -> &[u8];
+    // Frequency of each symbol in random sequences
+    fn frequencies(&self) -> &[(u8, f64)];
+    // Stationary frequency of each state, in 'alphabet' order, or in the
+    // order of the model's states if they aren't single symbols (codons)
+    fn equilibrium_frequencies(&self) -> &[f64];
+    // Symbols per site, a site of a codon model spans three nucleotides
+    fn site_width(&self) -> usize {
+        1
+    }
+    // States a site can be in, the size of the transition matrix
+    fn n_states(&self) -> usize {
+        self.alphabet().len()
+    }
+    // Labels of the states, in the order of 'equilibrium_frequencies'
+    fn states(&self) -> Vec<String> {
+        self.alphabet().iter().map(|&b| (b as char).to_string()).collect()
+    }
+    // Name of the model, e.g. "HKY" or "WAG"
+    fn name(&self) -> &str;
+    // Parameters the model was built with by name, besides its
+    // frequencies, the branch length scale included
+    fn parameters(&self) -> Vec<(&'static str, f64)>;
+    // Get a root sequence the model didn't draw itself ready to be mutated,
+    // mixtures assign its sites to their components
+    fn prepare_root(&self, _s: &mut Sequence, _rng: &mut StdRng) {}
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct HKY {
     nuc_frequencies: [f64; 4],
     bases: [u8; 4],
     kappa: f64,
     beta: f64,
-    scale: f64
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
 }
 
 impl HKY {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(pa: f64, pg: f64, pc: f64, pt: f64,
-        ba: u8, bg: u8, bc: u8, bt: u8, k: f64, s: f64) -> HKY {
+        ba: u8, bg: u8, bc: u8, bt: u8, k: f64, s: f64) -> Result<HKY, String> {
+        if k < 0.0 {
+            return Err(format!("HKY kappa can't be negative: {}", k));
+        }
+
+        // Frequencies must be positive, and are normalized to sum to 1.
+        // This also rules out the degenerate pa + pg == 0 or pc + pt == 0,
+        // which the transition probabilities divide by.
+        let [pa, pg, pc, pt] = check_frequencies([pa, pg, pc, pt])?;
+
         // Calculate beta
         let b: f64 = 1.0 /
                      (2.0 * (pa + pg) * (pc + pt) +
                       2.0 * k * ((pa * pg) + (pc * pt)));
 
-        HKY {
+        Ok(HKY {
             nuc_frequencies: [pa, pg, pc, pt],
             bases: [ba, bg, bc, bt],
             kappa: k,
             beta: b,
-            scale: s
-        }
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&[ba, bg, bc, bt],
+                &[pa, pg, pc, pt])),
+            cache: MatrixCache::new()
+        })
     }
-}
 
-impl Mutator for HKY {
-    fn mutate(&self, s: &Sequence, v: f64) -> Sequence {
+    // Same as 'new', but with beta = 1 instead of normalizing the mean
+    // substitution rate to 1. Branch lengths are then in units of the raw
+    // rate matrix, where a transversion to a base happens at its
+    // frequency, rather than in expected substitutions per site.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_unnormalized(pa: f64, pg: f64, pc: f64, pt: f64,
+        ba: u8, bg: u8, bc: u8, bt: u8, k: f64, s: f64) -> Result<HKY, String> {
+        let mut hky = HKY::new(pa, pg, pc, pt, ba, bg, bc, bt, k, s)?;
+        hky.beta = 1.0;
+        Ok(hky)
+    }
+
+    // Transition matrices built so far, see MatrixCache::builds
+    pub fn matrix_builds(&self) -> usize {
+        self.cache.builds()
+    }
+
+    // See JC69::transition_matrix
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
         let pa = self.nuc_frequencies[0];
         let pg = self.nuc_frequencies[1];
         let pc = self.nuc_frequencies[2];
@@ -45,7 +156,6 @@ impl Mutator for HKY {
 
         let b = self.beta;
         let k = self.kappa;
-        let scaled_v = v * self.scale;
 
         // TODO Move as much as possible to constructor
         let ag_ts_c = pa + pg + (pc + pt) * E.powf(-b * scaled_v);
@@ -79,65 +189,1506 @@ impl Mutator for HKY {
         let ptg: f64 =  pg * tv_c;
 
         // Build matrix
-        let matrix = arr2(&[
+        arr2(&[
             [paa, pag, pac, pat],
             [pga, pgg, pgc, pgt],
             [pca, pcg, pcc, pct],
             [pta, ptg, ptc, ptt]
+        ])
+    }
+}
+
+impl Mutator for HKY {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.nuc_frequencies
+    }
+
+    fn name(&self) -> &str {
+        "HKY"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("kappa", self.kappa), ("beta", self.beta),
+             ("scale", self.scale)]
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct JC69 {
+    bases: [u8; 4],
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl JC69 {
+    pub fn new(ba: u8, bg: u8, bc: u8, bt: u8, s: f64) -> JC69 {
+        JC69 {
+            bases: [ba, bg, bc, bt],
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&[ba, bg, bc, bt],
+                &EQUAL_FREQUENCIES)),
+            cache: MatrixCache::new()
+        }
+    }
+
+    // Probabilities of each base (row) ending up as each base (column)
+    // after a branch of length 'scaled_v', already multiplied by the scale
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // Every base is equally likely to change into any other
+        let e = E.powf(-4.0 / 3.0 * scaled_v);
+        let p_same: f64 = 0.25 + 0.75 * e;
+        let p_diff: f64 = 0.25 - 0.25 * e;
+
+        // Build matrix
+        let mut matrix = Array2::<f64>::from_elem((4, 4), p_diff);
+        matrix.diag_mut().fill(p_same);
+        matrix
+    }
+}
+
+impl Mutator for JC69 {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &EQUAL_FREQUENCIES
+    }
+
+    fn name(&self) -> &str {
+        "JC69"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("scale", self.scale)]
+    }
+}
+
+// Kimura two parameter model, HKY with equal base frequencies
+#[allow(clippy::upper_case_acronyms)]
+pub struct K80 {
+    bases: [u8; 4],
+    kappa: f64,
+    beta: f64,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl K80 {
+    pub fn new(ba: u8, bg: u8, bc: u8, bt: u8, k: f64, s: f64) ->
+        Result<K80, String> {
+        if k < 0.0 {
+            return Err(format!("K80 kappa can't be negative: {}", k));
+        }
+
+        Ok(K80 {
+            bases: [ba, bg, bc, bt],
+            kappa: k,
+            // HKY's beta with all frequencies at 0.25
+            beta: 4.0 / (2.0 + k),
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&[ba, bg, bc, bt],
+                &EQUAL_FREQUENCIES)),
+            cache: MatrixCache::new()
+        })
+    }
+
+    // See JC69::transition_matrix
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        let b = self.beta;
+        let k = self.kappa;
+
+        let tv_e = E.powf(-b * scaled_v);
+        let ts_e = E.powf(-(k + 1.0) / 2.0 * b * scaled_v);
+
+        let p_same: f64 = 0.25 + 0.25 * tv_e + 0.5 * ts_e;
+        let p_ts: f64   = 0.25 + 0.25 * tv_e - 0.5 * ts_e;
+        let p_tv: f64   = 0.25 - 0.25 * tv_e;
+
+        // Build matrix, transitions are A <-> G and C <-> T
+        arr2(&[
+            [p_same, p_ts,   p_tv,   p_tv  ],
+            [p_ts,   p_same, p_tv,   p_tv  ],
+            [p_tv,   p_tv,   p_same, p_ts  ],
+            [p_tv,   p_tv,   p_ts,   p_same]
+        ])
+    }
+}
+
+impl Mutator for K80 {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &EQUAL_FREQUENCIES
+    }
+
+    fn name(&self) -> &str {
+        "K80"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("kappa", self.kappa), ("beta", self.beta),
+             ("scale", self.scale)]
+    }
+}
+
+// Felsenstein 1981 model, unequal base frequencies with a single rate
+// (HKY with kappa = 1)
+#[allow(clippy::upper_case_acronyms)]
+pub struct F81 {
+    nuc_frequencies: [f64; 4],
+    bases: [u8; 4],
+    beta: f64,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl F81 {
+    pub fn new(freqs: [f64; 4], bases: [u8; 4], s: f64) ->
+        Result<F81, String> {
+        let freqs = check_frequencies(freqs)?;
+
+        // Normalize so branch lengths are expected substitutions per site
+        let b: f64 = 1.0 / (1.0 - freqs.iter().map(|p| p * p).sum::<f64>());
+
+        Ok(F81 {
+            nuc_frequencies: freqs,
+            bases,
+            beta: b,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&bases, &freqs)),
+            cache: MatrixCache::new()
+        })
+    }
+
+    // See JC69::transition_matrix
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // P_ij = pi_j + (delta_ij - pi_j) * e^(-beta * t)
+        let e = E.powf(-self.beta * scaled_v);
+        let pi = &self.nuc_frequencies;
+
+        Array2::from_shape_fn((4, 4), |(i, j)| {
+            let delta = if i == j { 1.0 } else { 0.0 };
+            pi[j] + (delta - pi[j]) * e
+        })
+    }
+}
+
+impl Mutator for F81 {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.nuc_frequencies
+    }
+
+    fn name(&self) -> &str {
+        "F81"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("beta", self.beta), ("scale", self.scale)]
+    }
+}
+
+// Amino acid analogue of JC69, all 20 residues are equally frequent and
+// interchange at the same rate
+pub struct PoissonAA {
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl PoissonAA {
+    pub fn new(s: f64) -> PoissonAA {
+        PoissonAA {
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&AMINO_ACIDS,
+                &POISSON_AA_FREQUENCIES)),
+            cache: MatrixCache::new()
+        }
+    }
+
+    fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        let e = E.powf(-20.0 / 19.0 * scaled_v);
+        let p_same: f64 = 0.05 + 0.95 * e;
+        let p_diff: f64 = 0.05 - 0.05 * e;
+
+        // Build matrix
+        let mut matrix = Array2::<f64>::from_elem((20, 20), p_diff);
+        matrix.diag_mut().fill(p_same);
+        matrix
+    }
+}
+
+impl Mutator for PoissonAA {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &AMINO_ACIDS,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &AMINO_ACIDS
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &POISSON_AA_FREQUENCIES
+    }
+
+    fn name(&self) -> &str {
+        "POISSON-AA"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("scale", self.scale)]
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct GTR {
+    nuc_frequencies: [f64; 4],
+    bases: [u8; 4],
+    // Exchangeabilities, as given
+    rates: [f64; 6],
+    rate_matrix: Array2<f64>,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl GTR {
+    // Rates are the exchangeabilities for AC, AG, AT, CG, CT and GT
+    // substitutions, frequencies and bases are in A, G, C, T order
+    pub fn new(rates: [f64; 6], freqs: [f64; 4], bases: [u8; 4], s: f64) ->
+        Result<GTR, String> {
+        if rates.iter().any(|&r| r < 0.0) {
+            return Err(format!("GTR rates can't be negative: {:?}", rates));
+        }
+
+        let pi = check_frequencies(freqs)?;
+        let [ac, ag, at, cg, ct, gt] = rates;
+
+        // Symmetric exchangeabilities, rows and columns in A, G, C, T order
+        let exchange = arr2(&[
+            [0.0, ag,  ac,  at ],
+            [ag,  0.0, cg,  gt ],
+            [ac,  cg,  0.0, ct ],
+            [at,  gt,  ct,  0.0]
         ]);
 
-        // Start mutating
-        let mut mutated = s.nucleotides.clone();
-        let mut rng = rand::thread_rng();
-        let generator = Uniform::from(0.0..1.0);
+        Ok(GTR {
+            nuc_frequencies: pi,
+            bases,
+            rates,
+            rate_matrix: reversible_rate_matrix(&exchange, &pi)?,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&bases, &pi)),
+            cache: MatrixCache::new()
+        })
+    }
+
+    fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // P(t) = exp(Qt)
+        expm(&(&self.rate_matrix * scaled_v))
+    }
+}
+
+impl Mutator for GTR {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.nuc_frequencies
+    }
+
+    fn name(&self) -> &str {
+        "GTR"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        let names = ["rate_ac", "rate_ag", "rate_at", "rate_cg", "rate_ct",
+            "rate_gt"];
+        let mut parameters: Vec<(&'static str, f64)> = names.iter().cloned()
+            .zip(self.rates.iter().cloned())
+            .collect();
+        parameters.push(("scale", self.scale));
+        parameters
+    }
+}
 
-        for n in mutated.iter_mut() {
-            let row = if *n == self.bases[0] { 0 }
-                else if  *n == self.bases[1] { 1 }
-                else if  *n == self.bases[2] { 2 }
-                else if  *n == self.bases[3] { 3 }
-                else { panic!("Unrecognized base {} in Sequence being
-                    mutated", n) };
-
-            // Weighted random choice from transition probabilities
-            let mut r: f64 = generator.sample(&mut rng);
-            let mut new_base: u8 = 0;
-            for i in 0..4 {
-                let f = matrix[[row, i]];
-
-                if r < f {
-                    new_base = self.bases[i];
-                    break
+// Empirical amino acid model (JTT, WAG or LG) from the aa_models tables
+pub struct EmpiricalAA {
+    model: AAModel,
+    aa_frequencies: [f64; 20],
+    rate_matrix: Array2<f64>,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl EmpiricalAA {
+    pub fn new(model: AAModel, s: f64) -> Result<EmpiricalAA, String> {
+        let freqs = model.frequencies();
+        let total: f64 = freqs.iter().sum();
+        let pi = freqs.map(|f| f / total);
+
+        // Unpack the lower triangle into a full symmetric matrix
+        let mut exchange = Array2::<f64>::zeros((20, 20));
+        let mut values = model.exchangeabilities().iter();
+        for i in 1..20 {
+            for j in 0..i {
+                let r = *values.next().unwrap();
+                exchange[[i, j]] = r;
+                exchange[[j, i]] = r;
+            }
+        }
+
+        let ret = EmpiricalAA {
+            model,
+            aa_frequencies: pi,
+            rate_matrix: reversible_rate_matrix(&exchange, &pi)?,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&AMINO_ACIDS, &pi)),
+            cache: MatrixCache::new()
+        };
+
+        // Make sure the embedded tables produce a sane model
+        for i in 0..20 {
+            for j in 0..i {
+                let forward  = pi[i] * ret.rate_matrix[[i, j]];
+                let backward = pi[j] * ret.rate_matrix[[j, i]];
+                if (forward - backward).abs() > 1e-10 {
+                    return Err(format!("{} rate matrix is not reversible \
+                        between residues {} and {}", model.name(), i, j));
                 }
+            }
+        }
 
-                r -= f;
+        for &t in &[0.01, 0.1, 1.0, 10.0] {
+            for (i, row) in ret.transition_matrix(t).outer_iter().enumerate() {
+                if (row.sum() - 1.0).abs() > 1e-6 ||
+                   row.iter().any(|&p| p < -1e-10) {
+                    return Err(format!("{} transition probabilities for \
+                        residue {} at t = {} are invalid", model.name(), i, t));
+                }
             }
+        }
+
+        Ok(ret)
+    }
+
+    fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // P(t) = exp(Qt)
+        expm(&(&self.rate_matrix * scaled_v))
+    }
+}
+
+impl Mutator for EmpiricalAA {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &AMINO_ACIDS,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &AMINO_ACIDS
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.aa_frequencies
+    }
+
+    fn name(&self) -> &str {
+        self.model.name()
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("scale", self.scale)]
+    }
+}
 
-            // Assert there's a valid new base
-            assert!(new_base != 0, "Something went terribly wrong in Mutator's
-                transition choice");
+#[allow(clippy::upper_case_acronyms)]
+pub struct TN93 {
+    nuc_frequencies: [f64; 4],
+    bases: [u8; 4],
+    kappa_r: f64,
+    kappa_y: f64,
+    beta: f64,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
 
-            // Modify the sequence with the new base
-            *n = new_base;
+impl TN93 {
+    // 'kr' scales purine (A <-> G) transitions and 'ky' pyrimidine (C <-> T)
+    // transitions, frequencies and bases are in A, G, C, T order
+    pub fn new(freqs: [f64; 4], bases: [u8; 4], kr: f64, ky: f64, s: f64) ->
+        Result<TN93, String> {
+        if kr < 0.0 || ky < 0.0 {
+            return Err(format!("TN93 kappas can't be negative: {}, {}",
+                kr, ky));
         }
 
-        // Build a Sequence object from mutated vec and freqs
-        let mut freq_table = Vec::<(u8, f64)>::new();
-        freq_table.push((self.bases[0], self.nuc_frequencies[0]));
-        freq_table.push((self.bases[1], self.nuc_frequencies[1]));
-        freq_table.push((self.bases[2], self.nuc_frequencies[2]));
-        freq_table.push((self.bases[3], self.nuc_frequencies[3]));
+        let [pa, pg, pc, pt] = check_frequencies(freqs)?;
+
+        // Calculate beta
+        let b: f64 = 1.0 /
+                     (2.0 * (pa + pg) * (pc + pt) +
+                      2.0 * (kr * pa * pg + ky * pc * pt));
+
+        Ok(TN93 {
+            nuc_frequencies: [pa, pg, pc, pt],
+            bases,
+            kappa_r: kr,
+            kappa_y: ky,
+            beta: b,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&bases, &[pa, pg, pc, pt])),
+            cache: MatrixCache::new()
+        })
+    }
+
+    // See JC69::transition_matrix
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        let pa = self.nuc_frequencies[0];
+        let pg = self.nuc_frequencies[1];
+        let pc = self.nuc_frequencies[2];
+        let pt = self.nuc_frequencies[3];
+        let pr = pa + pg;
+        let py = pc + pt;
+
+        let b = self.beta;
+        let kr = self.kappa_r;
+        let ky = self.kappa_y;
+
+        // Same closed form as HKY, with a separate kappa for each group
+        let ag_ts_c = pr + py * E.powf(-b * scaled_v);
+        let ag_ts_e = E.powf(-(pr * kr + py) * b * scaled_v);
+        let ct_ts_c = py + pr * E.powf(-b * scaled_v);
+        let ct_ts_e = E.powf(-(py * ky + pr) * b * scaled_v);
+        let tv_c    = 1.0 - E.powf(-b * scaled_v);
+
+        arr2(&[
+            [(pa * ag_ts_c + pg * ag_ts_e) / pr,
+             (pg * ag_ts_c - pg * ag_ts_e) / pr, pc * tv_c, pt * tv_c],
+            [(pa * ag_ts_c - pa * ag_ts_e) / pr,
+             (pg * ag_ts_c + pa * ag_ts_e) / pr, pc * tv_c, pt * tv_c],
+            [pa * tv_c, pg * tv_c,
+             (pc * ct_ts_c + pt * ct_ts_e) / py,
+             (pt * ct_ts_c - pt * ct_ts_e) / py],
+            [pa * tv_c, pg * tv_c,
+             (pc * ct_ts_c - pc * ct_ts_e) / py,
+             (pt * ct_ts_c + pc * ct_ts_e) / py]
+        ])
+    }
+}
+
+impl Mutator for TN93 {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.bases,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
 
-        Sequence::from_vec(mutated, &freq_table)
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
     }
 
-    fn random(&self, l: usize) -> Sequence {
-        let mut freq_table = Vec::<(u8, f64)>::new();
-        freq_table.push((self.bases[0], self.nuc_frequencies[0]));
-        freq_table.push((self.bases[1], self.nuc_frequencies[1]));
-        freq_table.push((self.bases[2], self.nuc_frequencies[2]));
-        freq_table.push((self.bases[3], self.nuc_frequencies[3]));
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.nuc_frequencies
+    }
 
-        Sequence::new(&freq_table, l)
+    fn name(&self) -> &str {
+        "TN93"
     }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("kappa1", self.kappa_r), ("kappa2", self.kappa_y),
+             ("beta", self.beta), ("scale", self.scale)]
+    }
+}
+
+// Genetic codes codon models can translate with, by NCBI translation table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeneticCode {
+    Standard,
+    VertebrateMitochondrial
+}
+
+impl GeneticCode {
+    // Code of NCBI translation table 'id'
+    pub fn from_id(id: usize) -> Option<GeneticCode> {
+        match id {
+            1 => Some(GeneticCode::Standard),
+            2 => Some(GeneticCode::VertebrateMitochondrial),
+            _ => None
+        }
+    }
+
+    // NCBI translation table id
+    pub fn id(&self) -> usize {
+        match self {
+            GeneticCode::Standard                => 1,
+            GeneticCode::VertebrateMitochondrial => 2
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneticCode::Standard                => "standard",
+            GeneticCode::VertebrateMitochondrial => "vertebrate mitochondrial"
+        }
+    }
+
+    // Amino acid encoded by a codon given as indices into A, G, C, T ('*'
+    // for stop codons)
+    pub fn translate(&self, codon: [usize; 3]) -> u8 {
+        // Tables are laid out in T, C, A, G order
+        const TCAG: [usize; 4] = [2, 3, 1, 0];
+        let index = 16 * TCAG[codon[0]] + 4 * TCAG[codon[1]] + TCAG[codon[2]];
+
+        match self {
+            GeneticCode::Standard                => STANDARD_CODE[index],
+            GeneticCode::VertebrateMitochondrial => VERTEBRATE_MITO_CODE[index]
+        }
+    }
+}
+
+// Goldman & Yang 1994 codon model over the sense codons of a genetic code
+// (61 for the standard one, 60 for vertebrate mitochondria). Codons
+// differing at a single position interchange at a rate scaled by kappa for
+// transitions and by omega (dN/dS) for nonsynonymous changes. Codon
+// frequencies are the products of their base frequencies (F1x4). Branch
+// lengths are expected substitutions per codon.
+pub struct Codon {
+    bases: [u8; 4],
+    kappa: f64,
+    omega: f64,
+    code: GeneticCode,
+    // Sense codons, in the order of the rows of the rate matrix
+    codons: Vec<[u8; 3]>,
+    codon_frequencies: Vec<f64>,
+    // Row of each codon by 16 * b1 + 4 * b2 + b3, bi being indices into
+    // 'bases', None for stop codons
+    states: [Option<usize>; 64],
+    rate_matrix: Array2<f64>,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl Codon {
+    // Codon model under the standard genetic code
+    pub fn new(freqs: [f64; 4], bases: [u8; 4], k: f64, omega: f64, s: f64)
+        -> Result<Codon, String> {
+        Codon::with_code(freqs, bases, k, omega, GeneticCode::Standard, s)
+    }
+
+    pub fn with_code(freqs: [f64; 4], bases: [u8; 4], k: f64, omega: f64,
+        code: GeneticCode, s: f64) -> Result<Codon, String> {
+        if k < 0.0 {
+            return Err(format!("Codon kappa can't be negative: {}", k));
+        }
+        if omega.is_nan() || omega < 0.0 {
+            return Err(format!("Codon omega can't be negative: {}", omega));
+        }
+
+        let freqs = check_frequencies(freqs)?;
+
+        let mut codons = Vec::<[u8; 3]>::new();
+        let mut residues = Vec::<u8>::new();
+        let mut pi = Vec::<f64>::new();
+        let mut states: [Option<usize>; 64] = [None; 64];
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    let aa = code.translate([i, j, k]);
+                    if aa == b'*' {
+                        continue
+                    }
+
+                    states[16 * i + 4 * j + k] = Some(codons.len());
+                    codons.push([bases[i], bases[j], bases[k]]);
+                    residues.push(aa);
+                    pi.push(freqs[i] * freqs[j] * freqs[k]);
+                }
+            }
+        }
+
+        let total: f64 = pi.iter().sum();
+        pi.iter_mut().for_each(|p| *p /= total);
+
+        // Only single nucleotide changes are allowed
+        let n = codons.len();
+        let mut exchange = Array2::<f64>::zeros((n, n));
+        for a in 0..n {
+            for b in 0..n {
+                let diffs: Vec<usize> = (0..3)
+                    .filter(|&p| codons[a][p] != codons[b][p])
+                    .collect();
+                if diffs.len() != 1 {
+                    continue
+                }
+
+                let x = base_index(&bases, codons[a][diffs[0]]);
+                let y = base_index(&bases, codons[b][diffs[0]]);
+                let mut r = 1.0;
+                if is_transition(x, y) {
+                    r *= k;
+                }
+                if residues[a] != residues[b] {
+                    r *= omega;
+                }
+
+                exchange[[a, b]] = r;
+            }
+        }
+
+        Ok(Codon {
+            bases,
+            kappa: k,
+            omega,
+            code,
+            codons,
+            codon_frequencies: pi.clone(),
+            states,
+            rate_matrix: reversible_rate_matrix(&exchange, &pi)?,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(&bases, &freqs)),
+            cache: MatrixCache::new()
+        })
+    }
+
+    fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // P(t) = exp(Qt)
+        expm(&(&self.rate_matrix * scaled_v))
+    }
+
+    // State of the codon starting at nucleotide 'position'
+    fn state(&self, codon: &[u8], position: usize) ->
+        Result<usize, MutationError> {
+        let mut index = 0;
+        for (i, &b) in codon.iter().enumerate() {
+            match self.bases.iter().position(|&x| x == b) {
+                Some(x) => index = 4 * index + x,
+                None    => return Err(MutationError::UnknownSymbol {
+                    symbol: b, position: position + i })
+            }
+        }
+
+        self.states[index].ok_or_else(|| MutationError::StopCodon {
+            codon: String::from_utf8_lossy(codon).into_owned(), position })
+    }
+}
+
+impl Mutator for Codon {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let v = v * self.scale;
+        let mut mutated = s.nucleotides.clone();
+        let generator = Uniform::from(0.0..1.0);
+
+        // One matrix per distinct rate seen on this branch
+        let mut matrices = HashMap::<u64, Arc<Array2<f64>>>::new();
+        for (i, codon) in mutated.chunks_mut(3).enumerate() {
+            // All three positions of a codon share its rate
+            let rate = s.site_rates().map_or(1.0, |r| r[3 * i]);
+            if rate == 0.0 {
+                continue
+            }
+
+            let matrix = matrices.entry(rate.to_bits())
+                .or_insert_with(|| {
+                    let m = self.cache.get_or_build(v * rate,
+                        |t| self.transition_matrix(t));
+                    debug_check_rows(&m, v * rate);
+                    m
+                });
+            let row = matrix.row(self.state(codon, 3 * i)?);
+            let next = weighted_choice(row.iter().copied(),
+                generator.sample(rng));
+            codon.copy_from_slice(&self.codons[next]);
+        }
+
+        let mut ret = Sequence::from_shared(mutated,
+            Arc::clone(&self.freq_table));
+        if let Some(site_rates) = s.site_rates() {
+            ret.set_site_rates(Arc::clone(site_rates));
+        }
+
+        Ok(ret)
+    }
+
+    // 'l' codons drawn from the codon frequencies
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        let generator = Uniform::from(0.0..1.0);
+        let mut nucleotides = Vec::<u8>::with_capacity(3 * l);
+        for _ in 0..l {
+            let codon = weighted_choice(self.codon_frequencies.iter().copied(),
+                generator.sample(rng));
+            nucleotides.extend_from_slice(&self.codons[codon]);
+        }
+
+        Sequence::from_shared(nucleotides, Arc::clone(&self.freq_table))
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.bases
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.codon_frequencies
+    }
+
+    fn site_width(&self) -> usize {
+        3
+    }
+
+    // Sense codons only
+    fn n_states(&self) -> usize {
+        self.codons.len()
+    }
+
+    fn states(&self) -> Vec<String> {
+        self.codons.iter()
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        "CODON"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("kappa", self.kappa), ("omega", self.omega),
+             ("genetic_code", self.code.id() as f64), ("scale", self.scale)]
+    }
+}
+
+// Model with a user supplied instantaneous rate matrix Q, whose rows and
+// columns follow 'symbols'. Q needn't be reversible, but 'freqs' should be
+// its equilibrium for root sequences to be at equilibrium too.
+pub struct CustomMatrix {
+    symbols: Vec<u8>,
+    state_frequencies: Vec<f64>,
+    rate_matrix: Array2<f64>,
+    scale: f64,
+    freq_table: Arc<Vec<(u8, f64)>>,
+    cache: MatrixCache
+}
+
+impl CustomMatrix {
+    // Off diagonal rates must not be negative, and each diagonal must make
+    // its row sum to 0 (within RATE_TOLERANCE of the row's rates). Q is
+    // rescaled so the mean substitution rate is 1.
+    pub fn new(q: Array2<f64>, freqs: &[f64], symbols: &[u8], s: f64) ->
+        Result<CustomMatrix, String> {
+        let n = symbols.len();
+        if q.dim() != (n, n) || freqs.len() != n {
+            return Err(format!("Rate matrix is {}x{} with {} frequencies, \
+                {} states need a {}x{} matrix", q.nrows(), q.ncols(),
+                freqs.len(), n, n, n));
+        }
+
+        for (i, row) in q.outer_iter().enumerate() {
+            let off_diagonal = row.iter().enumerate().filter(|&(j, _)| j != i)
+                .map(|(_, &r)| r);
+            if off_diagonal.clone().any(|r| r.is_nan() || r < 0.0) {
+                return Err(format!("Row {} of the rate matrix has negative \
+                    off diagonal rates", i + 1));
+            }
+
+            let leaving: f64 = off_diagonal.sum();
+            if (row[i] + leaving).abs() > RATE_TOLERANCE * leaving.max(1.0) {
+                return Err(format!("Row {} of the rate matrix sums to {}, \
+                    not 0", i + 1, row.sum()));
+            }
+        }
+
+        let total = frequency_total(freqs)?;
+        let pi: Vec<f64> = freqs.iter().map(|f| f / total).collect();
+
+        // Set diagonals exactly, then normalize to a mean rate of 1
+        let mut q = q;
+        for i in 0..n {
+            q[[i, i]] = 0.0;
+            q[[i, i]] = -q.row(i).sum();
+        }
+
+        let mean_rate: f64 = (0..n).map(|i| -pi[i] * q[[i, i]]).sum();
+        if mean_rate <= 0.0 {
+            return Err("Substitution rates can't all be zero".to_string());
+        }
+        q /= mean_rate;
+
+        Ok(CustomMatrix {
+            symbols: symbols.to_vec(),
+            rate_matrix: q,
+            scale: s,
+            freq_table: Arc::new(build_freq_table(symbols, &pi)),
+            state_frequencies: pi,
+            cache: MatrixCache::new()
+        })
+    }
+
+    // See JC69::transition_matrix
+    pub fn transition_matrix(&self, scaled_v: f64) -> Array2<f64> {
+        // P(t) = exp(Qt)
+        expm(&(&self.rate_matrix * scaled_v))
+    }
+}
+
+impl Mutator for CustomMatrix {
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let matrix_for = |t| self.cache.get_or_build(t,
+            |t| self.transition_matrix(t));
+
+        mutate_sites(matrix_for, v * self.scale, &self.symbols,
+            &self.freq_table, s, rng)
+    }
+
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        Sequence::new(&self.freq_table, l, rng)
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        &self.symbols
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.state_frequencies
+    }
+
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("scale", self.scale)]
+    }
+}
+
+// Mixture of models where every site evolves under one of the components
+// throughout. Sites are assigned a component, with probability given by the
+// weights, when root sequences are drawn, and descendants inherit the
+// assignment along with their site rates. Components must share their
+// alphabet, states and site width.
+pub struct Mixture {
+    components: Vec<Box<dyn Mutator>>,
+    // Normalized to sum to 1
+    weights: Vec<f64>,
+    state_frequencies: Vec<f64>,
+    freq_table: Arc<Vec<(u8, f64)>>
+}
+
+impl Mixture {
+    pub fn new(components: Vec<(Box<dyn Mutator>, f64)>) ->
+        Result<Mixture, String> {
+        let first = match components.first() {
+            Some((m, _)) => m,
+            None         => return Err("A mixture needs at least one \
+                component".to_string())
+        };
+
+        if let Some((_, w)) = components.iter()
+            .find(|(_, w)| !w.is_finite() || *w <= 0.0) {
+            return Err(format!("Mixture weights must be positive, got {}",
+                w));
+        }
+        if components.iter().any(|(m, _)| m.alphabet() != first.alphabet() ||
+            m.site_width() != first.site_width() ||
+            m.states() != first.states()) {
+            return Err("Mixture components must have the same alphabet and \
+                states".to_string());
+        }
+
+        let total: f64 = components.iter().map(|(_, w)| w).sum();
+        let weights: Vec<f64> = components.iter().map(|(_, w)| w / total)
+            .collect();
+
+        // Frequencies of the whole mixture, each component's weighted by
+        // the share of sites it gets
+        let alphabet = first.alphabet().to_vec();
+        let mut state_frequencies = vec![0.0; first.n_states()];
+        let mut symbol_frequencies = vec![0.0; alphabet.len()];
+        for ((m, _), w) in components.iter().zip(&weights) {
+            for (f, x) in state_frequencies.iter_mut()
+                .zip(m.equilibrium_frequencies()) {
+                *f += w * x;
+            }
+
+            let table = m.frequencies();
+            let table_total: f64 = table.iter().map(|(_, f)| f).sum();
+            for (f, b) in symbol_frequencies.iter_mut().zip(&alphabet) {
+                let x = table.iter().find(|(s, _)| s == b).map_or(0.0,
+                    |(_, x)| *x);
+                *f += w * x / table_total;
+            }
+        }
+
+        Ok(Mixture {
+            components: components.into_iter().map(|(m, _)| m).collect(),
+            weights,
+            state_frequencies,
+            freq_table: Arc::new(build_freq_table(&alphabet,
+                &symbol_frequencies))
+        })
+    }
+
+    // Component of each symbol of 'sites' sites, repeated for every symbol
+    // of a site
+    fn draw_classes(&self, sites: usize, rng: &mut StdRng) -> Vec<usize> {
+        let generator = Uniform::from(0.0..1.0);
+        let width = self.site_width();
+        (0..sites)
+            .flat_map(|_| {
+                let c = weighted_choice(self.weights.iter().copied(),
+                    generator.sample(rng));
+                std::iter::repeat_n(c, width)
+            })
+            .collect()
+    }
+}
+
+impl Mutator for Mixture {
+    // Each component mutates the symbols of its own sites
+    fn mutate(&self, s: &Sequence, v: f64, rng: &mut StdRng) ->
+        Result<Sequence, MutationError> {
+        let classes = s.site_classes()
+            .ok_or(MutationError::MissingSiteClasses)?;
+        let mut mutated = s.nucleotides.clone();
+
+        for (c, m) in self.components.iter().enumerate() {
+            let positions: Vec<usize> = (0..s.len())
+                .filter(|&i| classes[i] == c)
+                .collect();
+            if positions.is_empty() {
+                continue
+            }
+
+            let symbols = positions.iter().map(|&i| s.nucleotides[i])
+                .collect();
+            let mut sites = Sequence::from_vec(symbols, m.frequencies());
+            if let Some(rates) = s.site_rates() {
+                sites.set_site_rates(Arc::new(positions.iter()
+                    .map(|&i| rates[i]).collect()));
+            }
+
+            let evolved = m.mutate(&sites, v, rng)
+                .map_err(|e| e.remap(&positions))?;
+            for (&i, &b) in positions.iter().zip(&evolved.nucleotides) {
+                mutated[i] = b;
+            }
+        }
+
+        let mut ret = Sequence::from_shared(mutated,
+            Arc::clone(&self.freq_table));
+        if let Some(site_rates) = s.site_rates() {
+            ret.set_site_rates(Arc::clone(site_rates));
+        }
+        ret.set_site_classes(Arc::clone(classes));
+
+        Ok(ret)
+    }
+
+    // Sites get their component first, and are then drawn from it
+    fn random(&self, l: usize, rng: &mut StdRng) -> Sequence {
+        let width = self.site_width();
+        let classes = self.draw_classes(l, rng);
+
+        let mut counts = vec![0; self.components.len()];
+        for site in classes.chunks(width) {
+            counts[site[0]] += 1;
+        }
+        let drawn: Vec<Sequence> = self.components.iter().zip(counts)
+            .map(|(m, n)| m.random(n, rng))
+            .collect();
+
+        // Deal each component's sites out in order
+        let mut next = vec![0; self.components.len()];
+        let mut nucleotides = Vec::<u8>::with_capacity(classes.len());
+        for site in classes.chunks(width) {
+            let c = site[0];
+            let start = next[c] * width;
+            nucleotides.extend_from_slice(
+                &drawn[c].nucleotides[start..start + width]);
+            next[c] += 1;
+        }
+
+        let mut ret = Sequence::from_shared(nucleotides,
+            Arc::clone(&self.freq_table));
+        ret.set_site_classes(Arc::new(classes));
+        ret
+    }
+
+    fn alphabet(&self) -> &[u8] {
+        self.components[0].alphabet()
+    }
+
+    fn frequencies(&self) -> &[(u8, f64)] {
+        &self.freq_table
+    }
+
+    fn equilibrium_frequencies(&self) -> &[f64] {
+        &self.state_frequencies
+    }
+
+    fn site_width(&self) -> usize {
+        self.components[0].site_width()
+    }
+
+    fn n_states(&self) -> usize {
+        self.components[0].n_states()
+    }
+
+    fn states(&self) -> Vec<String> {
+        self.components[0].states()
+    }
+
+    fn name(&self) -> &str {
+        "mixture"
+    }
+
+    fn parameters(&self) -> Vec<(&'static str, f64)> {
+        vec![("components", self.components.len() as f64)]
+    }
+
+    // Roots given from outside have no site classes yet
+    fn prepare_root(&self, s: &mut Sequence, rng: &mut StdRng) {
+        if s.site_classes().is_none() {
+            let classes = self.draw_classes(s.len() / self.site_width(), rng);
+            s.set_site_classes(Arc::new(classes));
+        }
+    }
+}
+
+fn base_index(bases: &[u8; 4], b: u8) -> usize {
+    match bases.iter().position(|&x| x == b) {
+        Some(i) => i,
+        None    => panic!("Unrecognized base {} in codon", b)
+    }
+}
+
+// Whether two A, G, C, T indices are a purine or pyrimidine transition
+fn is_transition(x: usize, y: usize) -> bool {
+    x != y && x / 2 == y / 2
+}
+
+// Index picked by a uniform draw 'r' in [0, 1) among weights summing to 1
+fn weighted_choice<I: Iterator<Item = f64>>(weights: I, mut r: f64) -> usize {
+    let mut last = 0;
+    for (i, w) in weights.enumerate() {
+        if r < w {
+            return i
+        }
+
+        r -= w;
+        if w > 0.0 {
+            last = i;
+        }
+    }
+
+    // Rounding can leave a sliver past the last weight
+    last
+}
+
+// Check that user supplied base frequencies are positive and sum to 1
+// (within FREQ_TOLERANCE), returning them normalized to sum exactly to 1
+pub fn check_frequencies(freqs: [f64; 4]) -> Result<[f64; 4], String> {
+    let total = frequency_total(&freqs)?;
+    Ok(freqs.map(|f| f / total))
+}
+
+// Sum of 'freqs', which must be positive and sum to 1 within FREQ_TOLERANCE
+fn frequency_total(freqs: &[f64]) -> Result<f64, String> {
+    if freqs.iter().any(|&f| f.is_nan() || f <= 0.0) {
+        return Err(format!("Base frequencies must be positive: {:?}",
+            freqs));
+    }
+
+    let total: f64 = freqs.iter().sum();
+    if (total - 1.0).abs() > FREQ_TOLERANCE {
+        return Err(format!("Base frequencies must sum to 1, got {}",
+            total));
+    }
+
+    Ok(total)
+}
+
+// Build a time reversible rate matrix Q_ij = r_ij * pi_j from symmetric
+// exchangeabilities r and equilibrium frequencies pi, with diagonals making
+// each row sum to 0, normalized so the mean substitution rate is 1
+fn reversible_rate_matrix(exchange: &Array2<f64>, pi: &[f64]) ->
+    Result<Array2<f64>, String> {
+    let n = pi.len();
+    let mut q = Array2::<f64>::zeros((n, n));
+    for i in 0..n {
+        for j in 0..n {
+            if i != j {
+                q[[i, j]] = exchange[[i, j]] * pi[j];
+            }
+        }
+
+        q[[i, i]] = -q.row(i).sum();
+    }
+
+    let mean_rate: f64 = (0..n).map(|i| -pi[i] * q[[i, i]]).sum();
+    if mean_rate <= 0.0 {
+        return Err("Substitution rates can't all be zero".to_string());
+    }
+    q /= mean_rate;
+
+    Ok(q)
+}
+
+// Branch lengths closer than this share a cached transition matrix
+const CACHE_QUANTUM: f64 = 1e-9;
+// Past this many entries new matrices are built but not cached
+const CACHE_CAPACITY: usize = 4096;
+
+// Thread safe memo of transition matrices keyed by quantized branch length.
+// Matrices are always built at the quantized length, so the result doesn't
+// depend on which thread happened to fill a given entry first.
+pub struct MatrixCache {
+    matrices: RwLock<HashMap<i64, Arc<Array2<f64>>>>,
+    // Matrices built so far, cached or not
+    builds: AtomicUsize
+}
+
+impl Default for MatrixCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MatrixCache {
+    pub fn new() -> MatrixCache {
+        MatrixCache {
+            matrices: RwLock::new(HashMap::new()),
+            builds: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn get_or_build<F>(&self, v: f64, build: F) -> Arc<Array2<f64>>
+    where F: FnOnce(f64) -> Array2<f64>, {
+        let key = (v / CACHE_QUANTUM).round() as i64;
+
+        // A full cache can't take the matrix, so there's no point in
+        // waiting for the write lock
+        let full = {
+            let matrices = self.matrices.read().unwrap();
+            if let Some(m) = matrices.get(&key) {
+                return Arc::clone(m);
+            }
+            matrices.len() >= CACHE_CAPACITY
+        };
+
+        let matrix = Arc::new(build(key as f64 * CACHE_QUANTUM));
+        self.builds.fetch_add(1, Ordering::Relaxed);
+        if full {
+            return matrix;
+        }
+
+        let mut matrices = self.matrices.write().unwrap();
+        if matrices.len() < CACHE_CAPACITY {
+            matrices.entry(key).or_insert_with(|| Arc::clone(&matrix));
+        }
+
+        matrix
+    }
+
+    // Number of matrices built so far, each miss builds one
+    pub fn builds(&self) -> usize {
+        self.builds.load(Ordering::Relaxed)
+    }
+}
+
+// How far user supplied frequencies may stray from summing to 1
+const FREQ_TOLERANCE: f64 = 1e-3;
+
+// How far user supplied rate matrix rows may stray from summing to 0,
+// relative to the row's total rate
+const RATE_TOLERANCE: f64 = 1e-3;
+
+// How far transition matrix rows may stray from summing to 1
+const ROW_TOLERANCE: f64 = 1e-6;
+
+// Base frequencies of JC69 and K80
+const EQUAL_FREQUENCIES: [f64; 4] = [0.25, 0.25, 0.25, 0.25];
+
+// Standard genetic code, codons in T, C, A, G order
+const STANDARD_CODE: &[u8; 64] =
+    b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+// Vertebrate mitochondrial code (NCBI table 2): TGA is W, ATA is M and
+// AGA, AGG are stops
+const VERTEBRATE_MITO_CODE: &[u8; 64] =
+    b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSS**VVVVAAAADDEEGGGG";
+
+// One letter amino acid codes
+pub const AMINO_ACIDS: [u8; 20] = *b"ARNDCQEGHILKMFPSTWYV";
+const POISSON_AA_FREQUENCIES: [f64; 20] = [0.05; 20];
+
+// Matrix exponential by scaling and squaring: shrink the matrix by a power
+// of two until a truncated Taylor series converges quickly, then square
+// the result back up
+fn expm(a: &Array2<f64>) -> Array2<f64> {
+    let norm = a.outer_iter()
+        .map(|row| row.iter().map(|x| x.abs()).sum::<f64>())
+        .fold(0.0, f64::max);
+
+    let mut squarings: i32 = 0;
+    if norm > 0.5 {
+        squarings = (norm / 0.5).log2().ceil() as i32;
+    }
+    let scaled = a / 2.0_f64.powi(squarings);
+
+    let n = a.nrows();
+    let mut result = Array2::<f64>::eye(n);
+    let mut term   = Array2::<f64>::eye(n);
+    for k in 1..=16 {
+        term = term.dot(&scaled) / k as f64;
+        result += &term;
+    }
+
+    for _ in 0..squarings {
+        result = result.dot(&result);
+    }
+
+    result
+}
+
+fn build_freq_table(bases: &[u8], freqs: &[f64]) -> Vec<(u8, f64)> {
+    bases.iter().cloned().zip(freqs.iter().cloned()).collect()
+}
+
+// Replace every base in 's' by a weighted random choice from its row in a
+// transition probability matrix, whose rows and columns follow 'bases'.
+// 'matrix_for' gives the matrix for a branch length, which is 'v' scaled by
+// each site's rate if the sequence carries site rates.
+fn mutate_sites<F>(matrix_for: F, v: f64, bases: &[u8],
+    freq_table: &Arc<Vec<(u8, f64)>>, s: &Sequence, rng: &mut StdRng) ->
+    Result<Sequence, MutationError>
+where F: Fn(f64) -> Arc<Array2<f64>>, {
+    let mut mutated = s.nucleotides.clone();
+    let generator = Uniform::from(0.0..1.0);
+    // The matrix has a row and column per state, that is, per base
+    let n_states = bases.len();
+    let matrix_for = |t| {
+        let matrix = matrix_for(t);
+        debug_assert_eq!(matrix.dim(), (n_states, n_states));
+        debug_check_rows(&matrix, t);
+        matrix
+    };
+
+    // Lookup table from base to its row in the matrix
+    let mut rows: [Option<usize>; 256] = [None; 256];
+    for (i, &b) in bases.iter().enumerate() {
+        rows[b as usize] = Some(i);
+    }
+
+    match s.site_rates() {
+        None => {
+            let matrix = matrix_for(v);
+            for (i, n) in mutated.iter_mut().enumerate() {
+                *n = sample_base(&matrix, &rows, bases, *n, i,
+                    generator.sample(rng))?;
+            }
+        },
+        Some(site_rates) => {
+            // One matrix per distinct rate seen on this branch
+            let mut matrices = HashMap::<u64, Arc<Array2<f64>>>::new();
+            for (i, (n, &rate)) in mutated.iter_mut()
+                .zip(site_rates.iter()).enumerate() {
+                // Sites with no rate never change
+                if rate == 0.0 {
+                    continue
+                }
+
+                let matrix = matrices.entry(rate.to_bits())
+                    .or_insert_with(|| matrix_for(v * rate));
+                *n = sample_base(matrix, &rows, bases, *n, i,
+                    generator.sample(rng))?;
+            }
+        }
+    }
+
+    // Build a Sequence object from mutated vec and the model's freq table
+    let mut ret = Sequence::from_shared(mutated, Arc::clone(freq_table));
+    if let Some(site_rates) = s.site_rates() {
+        ret.set_site_rates(Arc::clone(site_rates));
+    }
+
+    Ok(ret)
+}
+
+// Check, in debug builds only, that every row of the transition matrix for
+// branch length 'v' is a probability distribution, so parameter bugs show up
+// here instead of as a skewed weighted choice
+fn debug_check_rows(matrix: &Array2<f64>, v: f64) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    for (i, row) in matrix.outer_iter().enumerate() {
+        let total: f64 = row.sum();
+        debug_assert!((total - 1.0).abs() < ROW_TOLERANCE,
+            "Row {} of the transition matrix for branch length {} sums to \
+            {}", i, v, total);
+        debug_assert!(row.iter().all(|&p| p > -ROW_TOLERANCE),
+            "Row {} of the transition matrix for branch length {} has \
+            negative probabilities: {}", i, v, row);
+    }
+}
+
+// Weighted choice of the base replacing 'n', found at 'position', using a
+// uniform draw 'r'
+fn sample_base(matrix: &Array2<f64>, rows: &[Option<usize>; 256],
+    bases: &[u8], n: u8, position: usize, r: f64) ->
+    Result<u8, MutationError> {
+    let row = match rows[n as usize] {
+        Some(r) => r,
+        None    => return Err(MutationError::UnknownSymbol {
+            symbol: n, position })
+    };
+
+    Ok(bases[weighted_choice(matrix.row(row).iter().copied(), r)])
 }
@@ -0,0 +1,419 @@
+use crate::mutator::Mutator;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, Error, ErrorKind, Write, BufWriter, StdoutLock,
+              stdout};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Default column at which FASTA sequence lines are wrapped
+pub const FASTA_WIDTH: usize = 60;
+// Width of the name field of PHYLIP records
+pub const PHYLIP_NAME_LEN: usize = 10;
+// Sequence columns in each block of interleaved PHYLIP
+const PHYLIP_BLOCK: usize = 60;
+// Characters that force a NEXUS name to be quoted
+const NEXUS_PUNCTUATION: &str = "()[]{}/\\,;:=*'\"`+-<>";
+
+// Kind of characters in an alignment, as NEXUS's DATATYPE
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy)]
+pub enum DataType {
+    DNA,
+    RNA,
+    Protein
+}
+
+impl DataType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataType::DNA     => "DNA",
+            DataType::RNA     => "RNA",
+            DataType::Protein => "PROTEIN"
+        }
+    }
+}
+
+// Output file, gzip compressed if its path ends in ".gz", or stdout if the
+// path is "-" (never compressed)
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Stdout(BufWriter<StdoutLock<'static>>)
+}
+
+impl OutputFile {
+    // Replace whatever 'path' held
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<OutputFile> {
+        OutputFile::open(path, false)
+    }
+
+    // Write after whatever 'path' holds. Appending to a ".gz" file adds
+    // another gzip member, which gzip tools read as one stream.
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<OutputFile> {
+        OutputFile::open(path, true)
+    }
+
+    fn open<P: AsRef<Path>>(path: P, append: bool) -> Result<OutputFile> {
+        if path.as_ref() == Path::new("-") {
+            return Ok(OutputFile::Stdout(BufWriter::new(stdout().lock())));
+        }
+
+        let gzip = path.as_ref().extension() == Some("gz".as_ref());
+        let file = BufWriter::new(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?);
+
+        if gzip {
+            Ok(OutputFile::Gzip(GzEncoder::new(file, Compression::default())))
+        } else {
+            Ok(OutputFile::Plain(file))
+        }
+    }
+
+    // Flush everything to disk, writing the gzip trailer if compressing.
+    // Dropping the file instead would silently ignore any errors.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            OutputFile::Plain(mut f)  => f.flush(),
+            OutputFile::Gzip(gz)      => gz.finish()?.flush(),
+            OutputFile::Stdout(mut f) => f.flush()
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            OutputFile::Plain(f)  => f.write(buf),
+            OutputFile::Gzip(gz)  => gz.write(buf),
+            OutputFile::Stdout(f) => f.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            OutputFile::Plain(f)  => f.flush(),
+            OutputFile::Gzip(gz)  => gz.flush(),
+            OutputFile::Stdout(f) => f.flush()
+        }
+    }
+}
+
+// Per taxon temporary files that sequences are appended to as trees are
+// simulated, so no more than one taxon's sequence needs to be in memory
+// when writing the output. Each append reopens the taxon's file, trading
+// throughput for not running out of file descriptors on many taxa.
+// The directory is removed when the spool is dropped.
+pub struct TaxonSpool {
+    dir: PathBuf,
+    files: BTreeMap<String, PathBuf>
+}
+
+impl TaxonSpool {
+    pub fn new() -> Result<TaxonSpool> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos()).unwrap_or(0);
+        let dir = std::env::temp_dir().join(format!("aminosim-{}-{}",
+            std::process::id(), nanos));
+        fs::create_dir(&dir)?;
+
+        Ok(TaxonSpool { dir, files: BTreeMap::new() })
+    }
+
+    // Add 'seq' to the end of taxon 'id's sequence
+    pub fn append(&mut self, id: &str, seq: &str) -> Result<()> {
+        if !self.files.contains_key(id) {
+            let path = self.dir.join(format!("{}.seq", self.files.len()));
+            self.files.insert(id.to_string(), path);
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.files[id])?;
+        file.write_all(seq.as_bytes())
+    }
+
+    // Ids of every taxon appended to, sorted
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|k| k.as_str())
+    }
+
+    // Full sequence of taxon 'id'
+    pub fn read(&self, id: &str) -> Result<String> {
+        match self.files.get(id) {
+            Some(path) => fs::read_to_string(path),
+            None       => Err(Error::new(ErrorKind::NotFound, format!(
+                "No sequence for taxon '{}'", id)))
+        }
+    }
+}
+
+impl Drop for TaxonSpool {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Couldn't remove temporary directory {}: {}",
+                self.dir.display(), e);
+        }
+    }
+}
+
+fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+            "Can't write a sequence with an empty id"));
+    }
+
+    Ok(())
+}
+
+fn check_ids(seqs: &[(String, String)]) -> Result<()> {
+    seqs.iter().try_for_each(|(k, _)| check_id(k))
+}
+
+// One "{id} {sequence}" pair per line
+pub fn write_phylip_like<W: Write>(out: &mut W,
+    seqs: &[(String, String)]) -> Result<()> {
+    check_ids(seqs)?;
+
+    for (k, v) in seqs {
+        write_phylip_like_record(out, k, v)?;
+    }
+
+    out.flush()
+}
+
+// A single line of 'write_phylip_like', for writing taxa one at a time
+pub fn write_phylip_like_record<W: Write>(out: &mut W, id: &str, seq: &str)
+    -> Result<()> {
+    check_id(id)?;
+    writeln!(out, "{} {}", id, seq)
+}
+
+// Length shared by all sequences, an error if they differ
+fn aligned_length(seqs: &[(String, String)]) -> Result<usize> {
+    let mut lengths = seqs.iter().map(|(_, v)| v.len());
+    let length = lengths.next().unwrap_or(0);
+
+    if lengths.any(|l| l != length) {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "Sequences have different lengths, can't write an alignment"));
+    }
+
+    Ok(length)
+}
+
+// Strict PHYLIP: a "{ntaxa} {nchars}" header, then records with names
+// padded (or truncated, with a warning) to PHYLIP_NAME_LEN. Interleaved
+// files split sequences in blocks, only the first of which has names.
+pub fn write_phylip<W: Write>(out: &mut W, seqs: &[(String, String)],
+    interleaved: bool) -> Result<()> {
+    check_ids(seqs)?;
+    let length = aligned_length(seqs)?;
+
+    let mut names = Vec::<String>::new();
+    for (k, _) in seqs {
+        let name: String = k.chars().take(PHYLIP_NAME_LEN).collect();
+        if name.len() < k.len() {
+            warn!("Truncating name '{}' to '{}' for PHYLIP", k, name);
+        }
+        if names.contains(&name) {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "Name '{}' is not unique once truncated for PHYLIP", name)));
+        }
+        names.push(name);
+    }
+
+    writeln!(out, "{} {}", seqs.len(), length)?;
+
+    let block = if interleaved { PHYLIP_BLOCK } else { length.max(1) };
+    for start in (0..length.max(1)).step_by(block) {
+        if start > 0 {
+            writeln!(out)?;
+        }
+
+        let end = (start + block).min(length);
+        for (name, (_, v)) in names.iter().zip(seqs) {
+            if start == 0 {
+                write!(out, "{:width$}", name, width=PHYLIP_NAME_LEN)?;
+            }
+            writeln!(out, "{}", &v[start..end])?;
+        }
+    }
+
+    out.flush()
+}
+
+// NEXUS token for 'name', single quoted if it has whitespace or
+// punctuation (with quotes doubled)
+fn nexus_name(name: &str) -> String {
+    if name.chars().any(|c| c.is_whitespace() ||
+        NEXUS_PUNCTUATION.contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+// NEXUS file with a TAXA block and a CHARACTERS block holding the matrix
+pub fn write_nexus<W: Write>(out: &mut W, seqs: &[(String, String)],
+    datatype: DataType) -> Result<()> {
+    check_ids(seqs)?;
+    let length = aligned_length(seqs)?;
+
+    let names: Vec<String> = seqs.iter().map(|(k, _)| nexus_name(k))
+        .collect();
+    let name_width = names.iter().map(|n| n.chars().count()).max()
+        .unwrap_or(0);
+
+    writeln!(out, "#NEXUS")?;
+    writeln!(out)?;
+    writeln!(out, "BEGIN TAXA;")?;
+    writeln!(out, "    DIMENSIONS NTAX={};", seqs.len())?;
+    writeln!(out, "    TAXLABELS")?;
+    for name in &names {
+        writeln!(out, "        {}", name)?;
+    }
+    writeln!(out, "    ;")?;
+    writeln!(out, "END;")?;
+    writeln!(out)?;
+    writeln!(out, "BEGIN CHARACTERS;")?;
+    writeln!(out, "    DIMENSIONS NCHAR={};", length)?;
+    writeln!(out, "    FORMAT DATATYPE={} MISSING=? GAP=-;",
+        datatype.name())?;
+    writeln!(out, "    MATRIX")?;
+    for (name, (_, v)) in names.iter().zip(seqs) {
+        writeln!(out, "        {:width$} {}", name, v, width=name_width)?;
+    }
+    writeln!(out, "    ;")?;
+    writeln!(out, "END;")?;
+
+    out.flush()
+}
+
+// RAxML style partition definitions, as read by RAxML and IQ-TREE, giving
+// the 1-based inclusive columns of each partition in the concatenated
+// alignment. 'partitions' has the data type ("DNA", "PROT"...) and width of
+// each partition, in alignment order.
+pub fn write_charsets<W: Write>(out: &mut W, partitions: &[(&str, usize)])
+    -> Result<()> {
+    let mut start: usize = 1;
+    for (i, (datatype, width)) in partitions.iter().enumerate() {
+        if *width == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "Partition {} has no columns", i)));
+        }
+
+        writeln!(out, "{}, part{} = {}-{}", datatype, i, start,
+            start + width - 1)?;
+        start += width;
+    }
+
+    out.flush()
+}
+
+// Tab separated table with the rate every column of the concatenated
+// alignment evolved at and the (0-based) partition it belongs to, rounded
+// to 'digits' decimals. 'partitions' has the width of each partition and
+// its rates, if they weren't all 1.
+pub fn write_site_rates<W: Write>(out: &mut W,
+    partitions: &[(usize, Option<&[f64]>)], digits: usize) -> Result<()> {
+    writeln!(out, "site\tpartition\trate")?;
+
+    let mut site: usize = 1;
+    for (i, (width, rates)) in partitions.iter().enumerate() {
+        for column in 0..*width {
+            let rate = rates.map_or(1.0, |r| r[column]);
+            writeln!(out, "{}\t{}\t{:.*}", site, i, digits, rate)?;
+            site += 1;
+        }
+    }
+
+    out.flush()
+}
+
+// Tab separated table with the length of every branch of each tree and the
+// sites that changed along it, rounded to 'digits' decimals. 'trees' has
+// the branches of each tree, as given by NTree::branch_substitutions.
+pub fn write_substitution_log<W: Write>(out: &mut W,
+    trees: &[Vec<(Option<&str>, f64, usize)>], digits: usize) -> Result<()> {
+    writeln!(out, "partition\tnode\tbranch_length\tsubstitutions")?;
+
+    for (i, branches) in trees.iter().enumerate() {
+        for (id, length, substitutions) in branches {
+            writeln!(out, "{}\t{}\t{:.*}\t{}", i, id.unwrap_or("-"), digits,
+                length, substitutions)?;
+        }
+    }
+
+    out.flush()
+}
+
+// TOML description of a run: 'settings' as top level keys, with values
+// already formatted as TOML, then a [[models]] table per model with its
+// name, parameters and equilibrium frequencies
+pub fn write_model_params<W: Write>(out: &mut W, settings: &[(&str, String)],
+    models: &[&dyn Mutator]) -> Result<()> {
+    for (key, value) in settings {
+        writeln!(out, "{} = {}", key, value)?;
+    }
+
+    for m in models {
+        writeln!(out, "\n[[models]]")?;
+        writeln!(out, "name = \"{}\"", m.name())?;
+        for (key, value) in m.parameters() {
+            writeln!(out, "{} = {}", key, value)?;
+        }
+
+        let states: Vec<String> = m.states().iter()
+            .map(|s| format!("\"{}\"", s))
+            .collect();
+        let freqs: Vec<String> = m.equilibrium_frequencies().iter()
+            .map(|f| f.to_string())
+            .collect();
+        writeln!(out, "states = [{}]", states.join(", "))?;
+        writeln!(out, "frequencies = [{}]", freqs.join(", "))?;
+    }
+
+    out.flush()
+}
+
+// ">{id}" header followed by the sequence, wrapped every 'width' columns
+// (a width of 0 writes the whole sequence on a single line)
+pub fn write_fasta<W: Write>(out: &mut W, seqs: &[(String, String)],
+    width: usize) -> Result<()> {
+    check_ids(seqs)?;
+
+    for (k, v) in seqs {
+        write_fasta_record(out, k, v, width)?;
+    }
+
+    out.flush()
+}
+
+// A single record of 'write_fasta', for writing taxa one at a time
+pub fn write_fasta_record<W: Write>(out: &mut W, id: &str, seq: &str,
+    width: usize) -> Result<()> {
+    check_id(id)?;
+    writeln!(out, ">{}", id)?;
+
+    if width == 0 {
+        return writeln!(out, "{}", seq);
+    }
+
+    for line in seq.as_bytes().chunks(width) {
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
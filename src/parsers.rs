@@ -1,63 +1,773 @@
 use crate::tree;
+use crate::aa_models::AAModel;
+use crate::mutator::{self, Mutator};
+use crate::rates::RateHeterogeneity;
 
+use ndarray::Array2;
 use rayon::prelude::*;
+use flate2::read::GzDecoder;
+use log::{info, warn};
 
+use std::collections::HashMap;
 use std::fs::File;
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::io::{Result, Lines, BufReader, BufRead,
-              stdout, Error, ErrorKind, Write};
+use std::io::{Result, Lines, BufReader, BufRead, Error, ErrorKind, stdin};
 
+// Longest stretch of an offending line quoted back in error messages
+const EXCERPT_LEN: usize = 60;
+
+// Tree strings and the line they start on, as read by 'read_trees'
+type TreeLines = Box<dyn Iterator<Item = Result<(usize, String)>>>;
+// NEXUS TRANSLATE table, from tip labels to taxon names
+type Translate = HashMap<String, String>;
+
+// Lines of 'filename', decompressed on the fly if it ends in ".gz", or of
+// stdin if it's "-" (never decompressed)
 fn read_lines<P>(filename: P) ->
-    Result<Lines<BufReader<File>>>
+    Result<Lines<Box<dyn BufRead>>>
 where P: AsRef<Path>, {
+    if filename.as_ref() == Path::new("-") {
+        let reader: Box<dyn BufRead> = Box::new(stdin().lock());
+        return Ok(reader.lines());
+    }
+
+    let gzip = filename.as_ref().extension() == Some("gz".as_ref());
     let file = File::open(filename)?;
-    Ok(BufReader::new(file).lines())
+
+    let reader: Box<dyn BufRead> = if gzip {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    Ok(reader.lines())
 }
 
-pub fn parse_newick_partitioned<P>(tree_fp: P, part_fp: P) ->
-    Result<Vec::<tree::NTree>>
+// Read one tree per non blank line from 'tree_fp', with its sequence
+// length on the matching line of 'part_fp', the i-th tree getting the i-th
+// partition. Blank lines and '#' comments in 'part_fp' are skipped. If
+// 'total_length' is given, the partitions must add up to it, and if
+// 'max_bases' is, they can't add up to more, so a typo can't make us
+// allocate more than the machine has. Both apply to every partition, even
+// if only the 'subset' of trees is kept. Returns the kept trees along with
+// the number of trees in the file.
+pub fn parse_newick_partitioned<P>(tree_fp: P, part_fp: P,
+    total_length: Option<usize>, max_bases: Option<usize>,
+    subset: Option<RangeInclusive<usize>>,
+    negative: tree::NegativeBranches) ->
+    Result<(Vec::<tree::NTree>, usize)>
 where P: AsRef<Path>, {
-    // Iterators
-    let mut tree_lines = read_lines(tree_fp)?;
-    let mut part_lines = read_lines(part_fp)?;
-    let iter = tree_lines.by_ref().zip(part_lines.by_ref());
+    // Iterators, skipping lines without trees or partitions
+    let (tree_lines, translate) = read_trees(tree_fp)?;
+    let mut tree_lines = tree_lines.filter(|l| match l {
+        Ok((_, line)) => !line.trim().is_empty(),
+        Err(_)        => true
+    });
+    let mut part_lines = read_lines(part_fp)?.enumerate()
+        .map(|(i, line)| line.map(|l| (i + 1, l)))
+        .filter(|l| match l {
+            Ok((_, line)) => !(line.trim().is_empty() ||
+                line.trim_start().starts_with('#')),
+            Err(_)        => true
+        });
     // Stats
     let mut line_counter: usize = 0;
     let mut part_counter: usize = 0;
-    // Results
+    let mut kept_bases: usize = 0;
+    // Results, along with the line each tree was read from
     let mut tree_vec = Vec::<tree::NTree>::new();
+    let mut line_nums = Vec::<usize>::new();
 
-    for (tree_line_o, part_line_o) in iter {
-        let tree_line = tree_line_o?;
-        let part_line = part_line_o?;
+    // Lines are paired up by hand, zip would silently drop what's left of
+    // the longer file
+    loop {
+        let (tree_line_o, part_line_o) = match (tree_lines.next(),
+            part_lines.next()) {
+            (Some(t), Some(p)) => (t, p),
+            (Some(t), None)    => {
+                let (line_num, tree_line) = t?;
+                return Err(line_error(line_num, "Tree has no partition, \
+                    there are more trees than partitions", tree_line.trim()));
+            },
+            (None, Some(p))    => {
+                let (line_num, part_line) = p?;
+                return Err(line_error(line_num, "Partition has no tree, \
+                    there are more partitions than trees", part_line.trim()));
+            },
+            (None, None)       => break
+        };
+        let (tree_line_num, tree_line) = tree_line_o?;
+        let (line_num, part_line) = part_line_o?;
+        let part_line = part_line.trim();
 
         // First, try and parse the partition number
         let part: usize = match part_line.parse::<usize>() {
             Ok(n) => n,
-            Err(_) => return Err(Error::new(ErrorKind::Other,
-                format!("Could not parse partition '{}' into number",
-                    part_line)))
+            Err(_) => return Err(line_error(line_num,
+                "Could not parse partition into number", part_line))
         };
 
-        part_counter += part;
+        if part == 0 {
+            return Err(line_error(line_num,
+                "Partition is 0, partitions must be positive", part_line));
+        }
+
+        part_counter = part_counter.saturating_add(part);
+        if let Some(max) = max_bases {
+            if part_counter > max {
+                return Err(line_error(line_num, &format!(
+                    "Partitions add up to more than the maximum of {} bases",
+                    max), part_line));
+            }
+        }
 
         // Now that we have a partition length, create preliminary tree objs
-        let tree_line = tree_line.trim();
-        assert!(tree_line.ends_with(';'),
-            "Incorrect Newick tree format, missing trailing ';'");
+        line_counter += 1;
+        if in_subset(&subset, line_counter) {
+            tree_vec.push(new_tree(part, &tree_line, tree_line_num)?);
+            line_nums.push(tree_line_num);
+            kept_bases += part;
+        }
+    }
+    info!("Done reading {} trees and partitions", line_counter);
+    check_any_trees(line_counter)?;
+    check_subset(&subset, line_counter)?;
+
+    if let Some(total) = total_length {
+        if part_counter != total {
+            return Err(Error::other(format!(
+                "Partitions add up to {} bases, expected a total of {}",
+                part_counter, total)));
+        }
+    }
+
+    let mut trees = build_trees(tree_vec, &line_nums, kept_bases,
+        negative)?;
+    translate_trees(&mut trees, &line_nums, &translate)?;
+    Ok((trees, line_counter))
+}
+
+// Read one tree per non blank line from 'tree_fp', all of them with
+// sequences of the same 'length'. See 'in_subset' for 'subset', and
+// 'parse_newick_partitioned' for what's returned.
+pub fn parse_newick_single<P>(tree_fp: P, length: usize,
+    subset: Option<RangeInclusive<usize>>,
+    negative: tree::NegativeBranches) ->
+    Result<(Vec::<tree::NTree>, usize)>
+where P: AsRef<Path>, {
+    let (tree_lines, translate) = read_trees(tree_fp)?;
+    // Stats
+    let mut line_counter: usize = 0;
+    // Results, along with the line each tree was read from
+    let mut tree_vec = Vec::<tree::NTree>::new();
+    let mut line_nums = Vec::<usize>::new();
 
-        let tree = tree::NTree::new(part, String::from(tree_line));
-        tree_vec.push(tree);
+    // Every tree gets the same sequence length, blank lines are skipped
+    for tree_line_o in tree_lines {
+        let (line_num, tree_line) = tree_line_o?;
+        if tree_line.trim().is_empty() {
+            continue
+        }
 
         line_counter += 1;
-        print!("\rDone reading {} trees and partitions", line_counter);
+        if in_subset(&subset, line_counter) {
+            tree_vec.push(new_tree(length, &tree_line, line_num)?);
+            line_nums.push(line_num);
+        }
+    }
+    info!("Done reading {} trees", line_counter);
+    check_any_trees(line_counter)?;
+    check_subset(&subset, line_counter)?;
+
+    let bases = tree_vec.len() * length;
+    let mut trees = build_trees(tree_vec, &line_nums, bases, negative)?;
+    translate_trees(&mut trees, &line_nums, &translate)?;
+    Ok((trees, line_counter))
+}
+
+// Tree strings of 'tree_fp', with the line each starts on. Files starting
+// with #NEXUS give the trees of their TREES blocks, along with the
+// TRANSLATE table for their tip labels (empty if there's none), anything
+// else is read one tree per line.
+fn read_trees<P>(tree_fp: P) -> Result<(TreeLines, Translate)>
+where P: AsRef<Path>, {
+    let mut lines = read_lines(tree_fp)?.enumerate().peekable();
+
+    // Blank lines may come before the #NEXUS token, but they're still lines
+    // of a plain file if it turns out not to be NEXUS
+    let mut leading = Vec::<(usize, String)>::new();
+    while let Some((i, line)) = lines.peek() {
+        match line {
+            Ok(l) if l.trim().is_empty() => {
+                leading.push((i + 1, l.clone()));
+                lines.next();
+            },
+            _ => break
+        }
+    }
+
+    let nexus = match lines.peek() {
+        Some((_, Ok(l))) => l.trim_start().to_uppercase()
+            .starts_with("#NEXUS"),
+        _                => false
+    };
+
+    if !nexus {
+        let rest = lines.map(|(i, line)| line.map(|l| (i + 1, l)));
+        return Ok((Box::new(leading.into_iter().map(Ok).chain(rest)),
+            HashMap::new()));
+    }
+
+    // The #NEXUS token isn't part of any command
+    let mut text = "\n".repeat(leading.len());
+    for (i, (_, line)) in lines.enumerate() {
+        let line = line?;
+        if i == 0 {
+            text.push_str(&line.trim_start()["#NEXUS".len()..]);
+        } else {
+            text.push_str(&line);
+        }
+        text.push('\n');
+    }
+
+    let (trees, translate) = parse_nexus_trees(&text)?;
+    info!("Read {} trees from a NEXUS file", trees.len());
+    Ok((Box::new(trees.into_iter().map(Ok)), translate))
+}
+
+// Trees and TRANSLATE table of the TREES blocks of the NEXUS file 'text',
+// trees as Newick strings (with their trailing ';') and the line they
+// start on
+fn parse_nexus_trees(text: &str) ->
+    Result<(Vec<(usize, String)>, Translate)> {
+    let mut trees = Vec::<(usize, String)>::new();
+    let mut translate = Translate::new();
+    let mut in_trees = false;
+
+    for (line_num, command) in nexus_commands(text)? {
+        let words = nexus_tokens(&strip_nexus_comments(&command));
+        let keyword = match words.first() {
+            Some(w) => w.to_uppercase(),
+            None    => continue
+        };
+
+        match keyword.as_str() {
+            "BEGIN" => in_trees = words.get(1)
+                .is_some_and(|w| w.eq_ignore_ascii_case("TREES")),
+            "END" | "ENDBLOCK" => in_trees = false,
+            "TRANSLATE" if in_trees => {
+                for pair in words[1..].split(|w| w == ",") {
+                    match pair {
+                        [key, name] => {
+                            translate.insert(key.clone(), name.clone());
+                        },
+                        [] => (),
+                        _  => return Err(line_error(line_num,
+                            "Malformed TRANSLATE entry, expected a label \
+                            and a taxon name", &pair.join(" ")))
+                    }
+                }
+            },
+            "TREE" | "UTREE" if in_trees => {
+                match command.split_once('=') {
+                    Some((_, newick)) => trees.push((line_num,
+                        format!("{};", newick.trim()))),
+                    None => return Err(line_error(line_num,
+                        "Malformed TREE command, expected 'TREE name = \
+                        (...)'", command.trim()))
+                }
+            },
+            _ => ()
+        }
+    }
+
+    Ok((trees, translate))
+}
+
+// Commands of NEXUS 'text', split on the ';' outside of quotes and
+// comments, with the line each starts on. Comments are kept, as trees can
+// be annotated with them.
+fn nexus_commands(text: &str) -> Result<Vec<(usize, String)>> {
+    let mut commands = Vec::<(usize, String)>::new();
+    let mut command = String::new();
+    let mut start: usize = 1;
+    let mut line: usize = 1;
+    let mut quoted = false;
+    let mut comment_depth: usize = 0;
+
+    for c in text.chars() {
+        if command.trim().is_empty() {
+            start = line;
+        }
+        if c == '\n' {
+            line += 1;
+        }
+
+        match c {
+            '\'' if comment_depth == 0 => quoted = !quoted,
+            '[' if !quoted => comment_depth += 1,
+            ']' if !quoted => comment_depth = comment_depth.saturating_sub(1),
+            ';' if !quoted && comment_depth == 0 => {
+                commands.push((start, std::mem::take(&mut command)));
+                continue
+            },
+            _ => ()
+        }
+
+        command.push(c);
+    }
+
+    if quoted || comment_depth > 0 {
+        return Err(line_error(start, "Unterminated quote or comment in NEXUS \
+            file", command.trim()));
+    }
+
+    Ok(commands)
+}
+
+// 'command' without its [...] comments
+fn strip_nexus_comments(command: &str) -> String {
+    let mut stripped = String::new();
+    let mut quoted = false;
+    let mut comment_depth: usize = 0;
+
+    for c in command.chars() {
+        match c {
+            '\'' if comment_depth == 0 => quoted = !quoted,
+            '[' if !quoted => {
+                comment_depth += 1;
+                continue
+            },
+            ']' if !quoted && comment_depth > 0 => {
+                comment_depth -= 1;
+                continue
+            },
+            _ => ()
+        }
+
+        if comment_depth == 0 {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+// Words of a NEXUS command, with commas as words of their own and quoted
+// words unquoted ('' stands for a literal quote)
+fn nexus_tokens(command: &str) -> Vec<String> {
+    let mut tokens = Vec::<String>::new();
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue
+        } else if c == ',' {
+            tokens.push(",".to_string());
+        } else if c == '\'' {
+            let mut token = String::new();
+            while let Some(x) = chars.next() {
+                if x == '\'' && chars.peek() == Some(&'\'') {
+                    chars.next();
+                    token.push('\'');
+                } else if x == '\'' {
+                    break;
+                } else {
+                    token.push(x);
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = c.to_string();
+            while let Some(&x) = chars.peek() {
+                if x.is_whitespace() || x == ',' {
+                    break;
+                }
+                token.push(x);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+// Apply a NEXUS TRANSLATE table to the tips of 'trees', read from
+// 'line_nums'. Fails on the first tree left with a repeated tip name.
+fn translate_trees(trees: &mut [tree::NTree], line_nums: &[usize],
+    translate: &Translate) -> Result<()> {
+    if translate.is_empty() {
+        return Ok(());
+    }
+
+    let results: Vec<std::result::Result<(), String>> = trees
+        .par_iter_mut()
+        .map(|t| t.translate_tips(translate))
+        .collect();
+
+    match results.into_iter().zip(line_nums).find_map(|(res, &line_num)|
+        res.err().map(|msg| (line_num, msg))) {
+        Some((line_num, msg)) => Err(Error::new(ErrorKind::InvalidData,
+            format!("Line {}: {}", line_num, msg))),
+        None                  => Ok(())
+    }
+}
+
+// Read (header, sequence) records from a FASTA file. Headers are kept up to
+// their first whitespace, sequences are upper cased with whitespace removed.
+pub fn parse_fasta<P>(fasta_fp: P) -> Result<Vec<(String, String)>>
+where P: AsRef<Path>, {
+    let mut records = Vec::<(String, String)>::new();
+
+    for (i, line_o) in read_lines(fasta_fp)?.enumerate() {
+        let line = line_o?;
+
+        if let Some(header) = line.strip_prefix('>') {
+            let id = header.split_whitespace().next().unwrap_or("");
+            records.push((id.to_string(), String::new()));
+        } else if !line.trim().is_empty() {
+            match records.last_mut() {
+                Some((_, seq)) => seq.extend(line.split_whitespace()
+                    .flat_map(|w| w.chars())
+                    .map(|c| c.to_ascii_uppercase())),
+                None => return Err(line_error(i + 1,
+                    "Sequence data before the first FASTA header", &line))
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+// Read one substitution model per non blank line of 'models_fp', as its
+// name followed by its whitespace separated parameters:
+//   HKY [A,G,C,T freqs] [kappa]
+//   K80 [kappa]
+//   F81 [A,G,C,T freqs]
+//   TN93 [A,G,C,T freqs] [kappa1] [kappa2]
+//   GTR [AC,AG,AT,CG,CT,GT rates] [A,G,C,T freqs]
+//   CODON [A,G,C,T freqs] [kappa] [omega] [NCBI genetic code]
+//   JC69, POISSON-AA, JTT, WAG or LG
+// Missing parameters take the same defaults as on the command line.
+pub fn parse_models<P>(models_fp: P, bases: [u8; 4], scale: f64) ->
+    Result<Vec<Box<dyn Mutator>>>
+where P: AsRef<Path>, {
+    let mut models = Vec::<Box<dyn Mutator>>::new();
+
+    for (i, model_line_o) in read_lines(models_fp)?.enumerate() {
+        let model_line = model_line_o?;
+        if model_line.trim().is_empty() {
+            continue
+        }
+
+        match model_from_spec(&model_line, bases, scale) {
+            Ok(m)    => models.push(m),
+            Err(msg) => return Err(line_error(i + 1, &msg, &model_line))
+        }
     }
 
+    Ok(models)
+}
+
+// Custom rate matrix model: a row per line of the instantaneous rate matrix
+// Q, then a line of equilibrium frequencies, with values separated by
+// whitespace or commas. Blank lines and lines starting with '#' are
+// skipped. 4 states are bases (in A, G, C, T order), 20 are amino acids
+// (in ARNDCQEGHILKMFPSTWYV order).
+pub fn parse_rate_matrix<P>(model_fp: P, bases: [u8; 4], scale: f64) ->
+    Result<mutator::CustomMatrix>
+where P: AsRef<Path>, {
+    let mut rows = Vec::<Vec<f64>>::new();
+    let mut last_line = (0, String::new());
+
+    for (i, line_o) in read_lines(model_fp)?.enumerate() {
+        let line = line_o?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue
+        }
+
+        let values = trimmed.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f64>()
+                .map_err(|_| format!("Could not parse '{}' into float", v)))
+            .collect::<std::result::Result<Vec<f64>, String>>()
+            .map_err(|msg| line_error(i + 1, &msg, &line))?;
+
+        if let Some(first) = rows.first() {
+            if values.len() != first.len() {
+                return Err(line_error(i + 1, &format!("Expected {} values, \
+                    got {}", first.len(), values.len()), &line));
+            }
+        }
+
+        rows.push(values);
+        last_line = (i + 1, line);
+    }
+
+    // Every line but the last is a row of Q
+    let freqs = match rows.pop() {
+        Some(f) => f,
+        None    => return Err(Error::new(ErrorKind::InvalidData,
+            "Rate matrix file is empty"))
+    };
+
+    let n = freqs.len();
+    if rows.len() != n {
+        return Err(line_error(last_line.0, &format!("Expected {} rows and \
+            a line of frequencies for {} states, got {} rows", n, n,
+            rows.len()), &last_line.1));
+    }
+
+    let symbols: &[u8] = match n {
+        4  => &bases,
+        20 => &mutator::AMINO_ACIDS,
+        _  => return Err(Error::new(ErrorKind::InvalidData, format!(
+            "Rate matrices need 4 (nucleotide) or 20 (amino acid) states, \
+            got {}", n)))
+    };
+
+    let q = Array2::from_shape_vec((n, n), rows.concat())
+        .expect("Rate matrix rows have the same length");
+
+    mutator::CustomMatrix::new(q, &freqs, symbols, scale)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+// Per site rate multipliers, one float per line, covering the sites of all
+// 'trees' in order. Blank lines and lines starting with '#' are skipped.
+// Returns fixed rates for each tree's sites.
+pub fn parse_site_rates<P>(rates_fp: P, trees: &[tree::NTree]) ->
+    Result<Vec<RateHeterogeneity>>
+where P: AsRef<Path>, {
+    let mut rates = Vec::<f64>::new();
+
+    for (i, line_o) in read_lines(rates_fp)?.enumerate() {
+        let line = line_o?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue
+        }
+
+        match trimmed.parse::<f64>() {
+            Ok(r) if r.is_finite() && r >= 0.0 => rates.push(r),
+            _ => return Err(line_error(i + 1,
+                "Site rates must be non-negative numbers", &line))
+        }
+    }
+
+    let sites: usize = trees.iter().map(|t| t.get_partition()).sum();
+    if rates.len() != sites {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "Got {} site rates, partitions add up to {} sites", rates.len(),
+            sites)));
+    }
+
+    let mut rest = rates.as_slice();
+    trees.iter().map(|t| {
+        let (tree_rates, tail) = rest.split_at(t.get_partition());
+        rest = tail;
+        RateHeterogeneity::fixed(tree_rates.to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }).collect()
+}
+
+fn model_from_spec(spec: &str, bases: [u8; 4], scale: f64) ->
+    std::result::Result<Box<dyn Mutator>, String> {
+    let mut fields = spec.split_whitespace();
+    let name = fields.next().unwrap_or("").to_uppercase();
+    let params: Vec<&str> = fields.collect();
+
+    let max_params = match name.as_str() {
+        "K80" | "F81"    => 1,
+        "HKY" | "GTR"    => 2,
+        "TN93"           => 3,
+        "CODON"          => 4,
+        _                => 0
+    };
+    if params.len() > max_params {
+        return Err(format!("Too many parameters for model {}", name));
+    }
+
+    let mut freqs: [f64; 4] = [0.25; 4];
+    let mut rates: [f64; 6] = [1.0; 6];
+
+    let model: Box<dyn Mutator> = match name.as_str() {
+        "HKY" => {
+            if let Some(p) = params.first() {
+                float_list(p, &mut freqs)?;
+            }
+            let kappa = float_param(params.get(1))?;
+
+            Box::new(mutator::HKY::new(freqs[0], freqs[1], freqs[2], freqs[3],
+                bases[0], bases[1], bases[2], bases[3], kappa, scale)?)
+        },
+        "TN93" => {
+            if let Some(p) = params.first() {
+                float_list(p, &mut freqs)?;
+            }
+            let kappa1 = float_param(params.get(1))?;
+            let kappa2 = float_param(params.get(2))?;
+
+            Box::new(mutator::TN93::new(freqs, bases, kappa1, kappa2, scale)?)
+        },
+        "GTR" => {
+            if let Some(p) = params.first() {
+                float_list(p, &mut rates)?;
+            }
+            if let Some(p) = params.get(1) {
+                float_list(p, &mut freqs)?;
+            }
+
+            Box::new(mutator::GTR::new(rates, freqs, bases, scale)?)
+        },
+        "K80" => {
+            let kappa = float_param(params.first())?;
+
+            Box::new(mutator::K80::new(bases[0], bases[1], bases[2],
+                bases[3], kappa, scale)?)
+        },
+        "F81" => {
+            if let Some(p) = params.first() {
+                float_list(p, &mut freqs)?;
+            }
+
+            Box::new(mutator::F81::new(freqs, bases, scale)?)
+        },
+        "CODON" => {
+            if let Some(p) = params.first() {
+                float_list(p, &mut freqs)?;
+            }
+            let kappa = float_param(params.get(1))?;
+            let omega = float_param(params.get(2))?;
+            let code = match params.get(3) {
+                Some(id) => id.parse::<usize>().ok()
+                    .and_then(mutator::GeneticCode::from_id)
+                    .ok_or(format!("Unknown genetic code '{}'", id))?,
+                None     => mutator::GeneticCode::Standard
+            };
+
+            Box::new(mutator::Codon::with_code(freqs, bases, kappa, omega,
+                code, scale)?)
+        },
+        "JC69" => Box::new(mutator::JC69::new(bases[0], bases[1], bases[2],
+            bases[3], scale)),
+        "POISSON-AA" => Box::new(mutator::PoissonAA::new(scale)),
+        "JTT" => Box::new(mutator::EmpiricalAA::new(AAModel::JTT, scale)?),
+        "WAG" => Box::new(mutator::EmpiricalAA::new(AAModel::WAG, scale)?),
+        "LG"  => Box::new(mutator::EmpiricalAA::new(AAModel::LG, scale)?),
+        _     => return Err(format!("Unknown model '{}'", name))
+    };
+
+    Ok(model)
+}
+
+// Optional float parameter of a model spec, 1 if missing
+fn float_param(param: Option<&&str>) -> std::result::Result<f64, String> {
+    match param {
+        Some(p) => p.parse::<f64>()
+            .map_err(|_| format!("Could not parse '{}' into float", p)),
+        None    => Ok(1.0)
+    }
+}
+
+// Fill 'dest' from a comma separated list of floats of the same length
+fn float_list(param: &str, dest: &mut [f64]) ->
+    std::result::Result<(), String> {
+    let values: Vec<&str> = param.split(',').collect();
+    if values.len() != dest.len() {
+        return Err(format!("Expected {} comma separated values, got {}",
+            dest.len(), values.len()));
+    }
+
+    for (d, v) in dest.iter_mut().zip(values) {
+        *d = v.trim().parse::<f64>()
+            .map_err(|_| format!("Could not parse '{}' into float", v))?;
+    }
+
+    Ok(())
+}
+
+// Error for a malformed input line, quoting (the start of) its content
+fn line_error(line_num: usize, msg: &str, content: &str) -> Error {
+    let excerpt = match content.char_indices().nth(EXCERPT_LEN) {
+        Some((i, _)) => format!("{}...", &content[..i]),
+        None         => content.to_string()
+    };
+
+    Error::new(ErrorKind::InvalidData,
+        format!("Line {}: {} ('{}')", line_num, msg, excerpt))
+}
+
+// Whether the 'n'-th tree of a file, counting from 1, is kept when only
+// the trees in the inclusive range 'subset' are wanted. Trees outside it
+// aren't even checked, let alone built.
+fn in_subset(subset: &Option<RangeInclusive<usize>>, n: usize) -> bool {
+    subset.as_ref().is_none_or(|r| r.contains(&n))
+}
+
+// There's nothing to simulate without trees
+fn check_any_trees(n_trees: usize) -> Result<()> {
+    if n_trees == 0 {
+        return Err(Error::new(ErrorKind::InvalidData,
+            "No trees were found in the tree file"));
+    }
+
+    Ok(())
+}
+
+// A subset must lie within the 'n_trees' trees read
+fn check_subset(subset: &Option<RangeInclusive<usize>>, n_trees: usize) ->
+    Result<()> {
+    match subset {
+        Some(r) if *r.end() > n_trees => Err(Error::new(
+            ErrorKind::InvalidInput, format!("Trees {}-{} were asked for, \
+                but there are only {} trees", r.start(), r.end(), n_trees))),
+        _ => Ok(())
+    }
+}
+
+fn new_tree(part: usize, tree_line: &str, line_num: usize) ->
+    Result<tree::NTree> {
+    let tree_line = tree_line.trim();
+    if !tree_line.ends_with(';') {
+        return Err(line_error(line_num,
+            "Incorrect Newick tree format, missing trailing ';'", tree_line));
+    }
+
+    Ok(tree::NTree::new(part, String::from(tree_line)))
+}
+
+fn build_trees(mut tree_vec: Vec::<tree::NTree>, line_nums: &[usize],
+    part_counter: usize, negative: tree::NegativeBranches) ->
+    Result<Vec::<tree::NTree>> {
     // Parse all trees in vector
-    println!("\nParsing {} trees that cover {} bases...",
-        line_counter, part_counter);
-    stdout().flush()?;
-    tree_vec.par_iter_mut().for_each(|t| t.build_from_newick());
+    info!("Parsing {} trees that cover {} bases...", tree_vec.len(),
+        part_counter);
+
+    let results: Vec<std::result::Result<(), String>> = tree_vec
+        .par_iter_mut()
+        .map(|t| t.build_from_newick(negative))
+        .collect();
+
+    // Report the first failing tree in file order, a failed build leaves the
+    // Newick string in place so it can be quoted back
+    let failed = results.into_iter().zip(tree_vec.iter().zip(line_nums))
+        .find_map(|(res, (t, &line_num))| res.err()
+            .map(|msg| line_error(line_num, &msg, t.newick())));
+
+    if let Some(e) = failed {
+        return Err(e);
+    }
+
+    for (t, line_num) in tree_vec.iter().zip(line_nums) {
+        if t.zeroed_branches() > 0 {
+            warn!("Set {} negative branch lengths to 0 on line {}",
+                t.zeroed_branches(), line_num);
+        }
+    }
 
     Ok(tree_vec)
 }
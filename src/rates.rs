@@ -0,0 +1,266 @@
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::distributions::{Uniform, Distribution};
+use rand_distr::Gamma;
+
+// Among-site rate heterogeneity. Every site gets a rate multiplier for
+// its branch lengths, drawn once when a tree's ancestral sequence is built
+// and then shared by every node of that tree.
+pub struct RateHeterogeneity {
+    gamma_alpha: Option<f64>,
+    // Rates of the discretized gamma categories, if any
+    categories: Option<Vec<f64>>,
+    // Proportion of invariant sites
+    pinvar: f64,
+    // Given rates, used as is instead of drawing any
+    fixed: Option<Vec<f64>>
+}
+
+impl RateHeterogeneity {
+    // Every site evolves at the same rate
+    pub fn uniform() -> RateHeterogeneity {
+        RateHeterogeneity {
+            gamma_alpha: None,
+            categories: None,
+            pinvar: 0.0,
+            fixed: None
+        }
+    }
+
+    // Every site evolves at the given rate, e.g. rates estimated from a real
+    // alignment
+    pub fn fixed(rates: Vec<f64>) -> Result<RateHeterogeneity, String> {
+        if let Some(r) = rates.iter().find(|r| !r.is_finite() || **r < 0.0) {
+            return Err(format!("Site rates must be non-negative numbers, \
+                got {}", r));
+        }
+
+        Ok(RateHeterogeneity {
+            gamma_alpha: None,
+            categories: None,
+            pinvar: 0.0,
+            fixed: Some(rates)
+        })
+    }
+
+    // Site rates drawn from a Gamma(alpha, 1/alpha) distribution (mean 1),
+    // either continuously or from 'cats' equally probable categories
+    // represented by their mean rate (Yang 1994)
+    pub fn gamma(alpha: f64, cats: Option<usize>) ->
+        Result<RateHeterogeneity, String> {
+        if alpha.is_nan() || alpha <= 0.0 {
+            return Err(format!("Gamma shape must be positive, got {}",
+                alpha));
+        }
+
+        let categories = match cats {
+            Some(0) => return Err("Need at least one gamma category"
+                .to_string()),
+            Some(n) => Some(discrete_gamma(alpha, n)),
+            None    => None
+        };
+
+        Ok(RateHeterogeneity {
+            gamma_alpha: Some(alpha),
+            categories,
+            pinvar: 0.0,
+            fixed: None
+        })
+    }
+
+    // Make a proportion 'p' of the sites invariant (rate 0). The remaining
+    // sites have their rates scaled by 1 / (1 - p) so the mean rate stays 1
+    // and branch lengths are still expected substitutions per site.
+    pub fn with_pinvar(mut self, p: f64) -> Result<RateHeterogeneity, String> {
+        if !(0.0..1.0).contains(&p) {
+            return Err(format!("Proportion of invariant sites must be in \
+                [0, 1), got {}", p));
+        }
+
+        self.pinvar = p;
+        Ok(self)
+    }
+
+    // Settings by name, leaving out the ones that aren't used. Fixed rates
+    // are left out too, they're better described by where they came from.
+    pub fn parameters(&self) -> Vec<(&'static str, f64)> {
+        let mut parameters = Vec::new();
+        if let Some(alpha) = self.gamma_alpha {
+            parameters.push(("gamma_alpha", alpha));
+        }
+        if let Some(categories) = &self.categories {
+            parameters.push(("gamma_categories", categories.len() as f64));
+        }
+        if self.pinvar > 0.0 {
+            parameters.push(("pinvar", self.pinvar));
+        }
+
+        parameters
+    }
+
+    // Draw rates for 'l' sites, None if all sites share the same rate.
+    // Fixed rates are returned as they are, and must be 'l'.
+    pub fn draw(&self, l: usize, rng: &mut StdRng) -> Option<Vec<f64>> {
+        if let Some(fixed) = &self.fixed {
+            assert!(fixed.len() == l, "Fixed site rates don't match the \
+                number of sites");
+            return Some(fixed.clone());
+        }
+        if self.gamma_alpha.is_none() && self.pinvar == 0.0 {
+            return None;
+        }
+
+        let mut rates: Vec<f64> = match (&self.categories, self.gamma_alpha) {
+            (Some(c), _) => {
+                let generator = Uniform::from(0..c.len());
+                (0..l).map(|_| c[generator.sample(rng)]).collect()
+            },
+            (None, Some(alpha)) => {
+                let generator = Gamma::new(alpha, 1.0 / alpha).unwrap();
+                (0..l).map(|_| generator.sample(rng)).collect()
+            },
+            (None, None) => vec![1.0; l]
+        };
+
+        if self.pinvar > 0.0 {
+            let n_invariant = (self.pinvar * l as f64).round() as usize;
+            rates.iter_mut().for_each(|r| *r /= 1.0 - self.pinvar);
+            for i in index::sample(rng, l, n_invariant).into_iter() {
+                rates[i] = 0.0;
+            }
+        }
+
+        Some(rates)
+    }
+}
+
+// Mean rate of each of 'n' equally probable categories of a mean 1 gamma
+fn discrete_gamma(alpha: f64, n: usize) -> Vec<f64> {
+    // Category boundaries, on the scale of a unit rate gamma
+    let mut bounds = vec![0.0];
+    for k in 1..n {
+        bounds.push(gamma_quantile(alpha, k as f64 / n as f64));
+    }
+    bounds.push(f64::INFINITY);
+
+    // The mean of a category is given by the incomplete gamma of alpha + 1
+    let mut rates: Vec<f64> = bounds.windows(2)
+        .map(|w| n as f64 * (lower_gamma(alpha + 1.0, w[1]) -
+                             lower_gamma(alpha + 1.0, w[0])))
+        .collect();
+
+    // Get rid of rounding errors so the mean is exactly 1
+    let mean: f64 = rates.iter().sum::<f64>() / n as f64;
+    rates.iter_mut().for_each(|r| *r /= mean);
+
+    rates
+}
+
+// Natural log of the gamma function (Lanczos approximation)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7
+    ];
+
+    if x < 0.5 {
+        // Reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+
+    let x = x - 1.0;
+    let mut sum = COEFS[0];
+    for (i, &c) in COEFS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+
+    let t = x + 7.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t +
+        sum.ln()
+}
+
+// Regularized lower incomplete gamma function P(a, x)
+fn lower_gamma(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x.is_infinite() {
+        return 1.0;
+    }
+
+    let ln_prefix = a * x.ln() - x - ln_gamma(a);
+
+    if x < a + 1.0 {
+        // Series expansion
+        let mut term = 1.0 / a;
+        let mut sum  = term;
+        for n in 1..500 {
+            term *= x / (a + n as f64);
+            sum  += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+
+        (sum.ln() + ln_prefix).exp()
+    } else {
+        // Continued fraction for the upper function (modified Lentz)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for n in 1..500 {
+            let an = -(n as f64) * (n as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+
+        1.0 - (ln_prefix + h.ln()).exp()
+    }
+}
+
+// Inverse of P(a, x) with respect to x, found by bisection
+fn gamma_quantile(a: f64, p: f64) -> f64 {
+    let mut lo = 0.0;
+    let mut hi = a.max(1.0);
+    while lower_gamma(a, hi) < p {
+        hi *= 2.0;
+    }
+
+    for _ in 0..200 {
+        let mid = 0.5 * (lo + hi);
+        if lower_gamma(a, mid) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+
+        if hi - lo < 1e-14 * hi {
+            break;
+        }
+    }
+
+    0.5 * (lo + hi)
+}
@@ -1,19 +1,57 @@
-use rand::rngs::ThreadRng;
+use crate::stats::hamming_distance;
+
+use rand::Rng;
 use rand::distributions::{Uniform, Distribution};
 
+use std::ops::Range;
+use std::sync::Arc;
+
 #[derive(Clone)]
 pub struct Sequence {
     pub nucleotides: Vec<u8>,
-    size: usize,
-    freq_table: Vec<(u8, f64)>,
-    max_freq: f64
+    freq_table: Arc<Vec<(u8, f64)>>,
+    max_freq: f64,
+    // Per site branch length multipliers, shared by a whole tree
+    site_rates: Option<Arc<Vec<f64>>>,
+    // Mixture component of each symbol's site, shared by a whole tree
+    site_classes: Option<Arc<Vec<usize>>>
+}
+
+// Bases an IUPAC nucleotide ambiguity code stands for, with T for T or U
+fn ambiguity_bases(code: u8) -> Option<&'static [u8]> {
+    match code {
+        b'R' => Some(b"AG"),
+        b'Y' => Some(b"CT"),
+        b'S' => Some(b"CG"),
+        b'W' => Some(b"AT"),
+        b'K' => Some(b"GT"),
+        b'M' => Some(b"AC"),
+        b'B' => Some(b"CGT"),
+        b'D' => Some(b"AGT"),
+        b'H' => Some(b"ACT"),
+        b'V' => Some(b"ACG"),
+        b'N' => Some(b"ACGT"),
+        _    => None
+    }
 }
 
-fn get_cumulative(t: &Vec<(u8, f64)>) -> f64 {
+// Error naming the first symbol of 's' that isn't 'valid', if there's one
+fn check_symbols(s: &[u8], valid: impl Fn(u8) -> bool, alphabet: &str) ->
+    Result<(), String> {
+    match s.iter().position(|&b| !valid(b)) {
+        Some(pos) => Err(format!("Symbol '{}' at position {} is not one of {}",
+            s[pos].escape_ascii(), pos + 1, alphabet)),
+        None      => Ok(())
+    }
+}
+
+fn get_cumulative(t: &[(u8, f64)]) -> f64 {
     let mut cumulative_freq: f64 = 0.0;
 
     // Build with cumulative values
-    assert!(t.len() > 0, "Empty frequency table");
+    assert!(!t.is_empty(), "Empty frequency table");
+    debug_assert!(t.iter().all(|&(c, _)| c.is_ascii()),
+        "Sequence symbols must be ASCII");
     for &(_, f) in t.iter() {
         // Validate values in table
         assert!(f > 0.0, "Can't have nucleotide frequencies <= 0");
@@ -25,37 +63,119 @@ fn get_cumulative(t: &Vec<(u8, f64)>) -> f64 {
 }
 
 impl Sequence {
-    pub fn new(t: &Vec<(u8, f64)>, l: usize) -> Sequence {
+    pub fn new<R: Rng + ?Sized>(t: &[(u8, f64)], l: usize, rng: &mut R) ->
+        Sequence {
         let cumulative_freq = get_cumulative(t);
 
         // Build our empty sequence
         let mut ret = Sequence {
             nucleotides: Vec::<u8>::new(),
-            size: 0,
-            freq_table: t.clone(),
-            max_freq: cumulative_freq
+            freq_table: Arc::new(t.to_vec()),
+            max_freq: cumulative_freq,
+            site_rates: None,
+            site_classes: None
         };
 
         // Append 'l' nucleotides to our sequence
-        ret.append(l);
+        ret.append(l, rng);
         ret
     }
 
-    pub fn from_vec(s: Vec<u8>, t: &Vec<(u8, f64)>) -> Sequence {
-        let cumulative_freq = get_cumulative(t);
+    pub fn from_vec(s: Vec<u8>, t: &[(u8, f64)]) -> Sequence {
+        Sequence::from_shared(s, Arc::new(t.to_vec()))
+    }
+
+    // Same as 'from_vec', sharing the frequency table instead of copying it.
+    // Models keep theirs in an Arc and hand it to every sequence they make,
+    // so a tree's sequences don't each carry a copy.
+    pub fn from_shared(s: Vec<u8>, t: Arc<Vec<(u8, f64)>>) -> Sequence {
+        let cumulative_freq = get_cumulative(&t);
+
+        debug_assert!(s.is_ascii(), "Sequence symbols must be ASCII");
 
         // Attach given vec to our Sequence object
-        let len = s.len();
         Sequence {
             nucleotides: s,
-            size: len,
-            freq_table: t.clone(),
-            max_freq: cumulative_freq
+            freq_table: t,
+            max_freq: cumulative_freq,
+            site_rates: None,
+            site_classes: None
+        }
+    }
+
+    // Sequence of the given symbols, which must all be in 'alphabet', or be
+    // IUPAC ambiguity codes if 'alphabet' has the four nucleotides, to be
+    // settled with 'resolve_ambiguities'. Symbols appended afterwards are
+    // drawn uniformly from 'alphabet'.
+    pub fn from_ascii(s: &[u8], alphabet: &[u8]) -> Result<Sequence, String> {
+        let nucleotides = alphabet.len() == 4;
+        check_symbols(s, |b| alphabet.contains(&b) ||
+            (nucleotides && ambiguity_bases(b).is_some()),
+            &String::from_utf8_lossy(alphabet))?;
+
+        let freq_table: Vec<(u8, f64)> = alphabet.iter()
+            .map(|&b| (b, 1.0))
+            .collect();
+
+        Ok(Sequence::from_vec(s.to_vec(), &freq_table))
+    }
+
+    // Sequence of the given string keeping the frequencies of 'freq_table',
+    // whose symbols every character must be one of
+    pub fn from_str(s: &str, freq_table: &[(u8, f64)]) ->
+        Result<Sequence, String> {
+        let alphabet: String = freq_table.iter()
+            .map(|&(c, _)| c as char)
+            .collect();
+        check_symbols(s.as_bytes(), |b| freq_table.iter().any(|&(c, _)| c == b),
+            &alphabet)?;
+
+        Ok(Sequence::from_vec(s.as_bytes().to_vec(), freq_table))
+    }
+
+    // Replace every IUPAC ambiguity code that isn't a symbol of 'freq_table'
+    // by one of the bases it stands for, drawn according to their
+    // frequencies in 'freq_table'. U and T are interchangeable, so codes
+    // resolve to whichever the table has.
+    pub fn resolve_ambiguities<R: Rng + ?Sized>(&mut self,
+        freq_table: &[(u8, f64)], rng: &mut R) {
+        for n in self.nucleotides.iter_mut() {
+            if freq_table.iter().any(|&(c, _)| c == *n) {
+                continue
+            }
+
+            let bases = match ambiguity_bases(*n) {
+                Some(b) => b,
+                None    => continue
+            };
+            let candidates: Vec<(u8, f64)> = freq_table.iter()
+                .filter(|&&(c, _)| bases.contains(&c) ||
+                    (c == b'U' && bases.contains(&b'T')))
+                .copied()
+                .collect();
+            if candidates.is_empty() {
+                continue
+            }
+
+            let total: f64 = candidates.iter().map(|&(_, f)| f).sum();
+            let mut r = rng.gen_range(0.0, total);
+            *n = candidates[candidates.len() - 1].0;
+            for &(c, f) in &candidates {
+                if r < f {
+                    *n = c;
+                    break;
+                }
+
+                r -= f;
+            }
         }
     }
 
-    fn sample(&self, generator: Uniform<f64>, mut rng: ThreadRng) -> u8 {
-        let mut r: f64 = generator.sample(&mut rng);
+    // Draw a symbol from the frequency table, any RNG will do so seeded
+    // ones can be passed in for reproducible sequences
+    fn sample<R: Rng + ?Sized>(&self, generator: Uniform<f64>, rng: &mut R)
+        -> u8 {
+        let mut r: f64 = generator.sample(rng);
 
         for &(c, f) in self.freq_table.iter() {
             if r < f {
@@ -65,33 +185,155 @@ impl Sequence {
             r -= f;
         }
 
-        assert!(false, "Something went terribly wrong in Sequence's sampler");
-        return 0
+        panic!("Something went terribly wrong in Sequence's sampler");
     }
 
-    pub fn append(&mut self, l: usize) {
-        // Initialize RNG
-        let rng = rand::thread_rng();
+    pub fn append<R: Rng + ?Sized>(&mut self, l: usize, rng: &mut R) {
         let generator = Uniform::from(0.0..self.max_freq);
 
         for _ in 0..l {
             self.nucleotides.push(self.sample(generator, rng));
         }
+    }
 
-        self.size += l;
+    // Insert the symbols of 'inserted' before position 'at'. New sites
+    // evolve at rate 1 if the sequence has site rates, which stop being
+    // shared with the tree. If the sequence has mixture classes, the new
+    // sites keep the ones 'inserted' was drawn with.
+    pub fn insert(&mut self, at: usize, inserted: &Sequence) {
+        let n = inserted.len();
+        self.nucleotides.splice(at..at, inserted.nucleotides.iter().copied());
+        if let Some(rates) = &mut self.site_rates {
+            Arc::make_mut(rates).splice(at..at, std::iter::repeat_n(1.0, n));
+        }
+        if let Some(classes) = &mut self.site_classes {
+            let new_classes = inserted.site_classes.as_ref()
+                .expect("Sites inserted into a sequence with mixture classes \
+                    need classes of their own");
+            Arc::make_mut(classes).splice(at..at,
+                new_classes.iter().copied());
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn print(&self) {
-        unsafe {
-            println!("{}", std::str::from_utf8_unchecked(&self.nucleotides));
+    // Remove the symbols in 'range', along with their site rates and classes
+    pub fn delete(&mut self, range: Range<usize>) {
+        if let Some(rates) = &mut self.site_rates {
+            Arc::make_mut(rates).drain(range.clone());
         }
+        if let Some(classes) = &mut self.site_classes {
+            Arc::make_mut(classes).drain(range.clone());
+        }
+        self.nucleotides.drain(range);
     }
 
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> &str {
-        unsafe {
-            return std::str::from_utf8_unchecked(&self.nucleotides);
+    // Symbols 'start' to 'end' (exclusive), with their site rates and
+    // classes and the same frequency table
+    pub fn slice(&self, start: usize, end: usize) -> Sequence {
+        assert!(start <= end && end <= self.len(), "Slice {}..{} is out of \
+            bounds for a sequence of length {}", start, end, self.len());
+
+        Sequence {
+            nucleotides: self.nucleotides[start..end].to_vec(),
+            freq_table: Arc::clone(&self.freq_table),
+            max_freq: self.max_freq,
+            site_rates: self.site_rates.as_ref()
+                .map(|r| Arc::new(r[start..end].to_vec())),
+            site_classes: self.site_classes.as_ref()
+                .map(|c| Arc::new(c[start..end].to_vec()))
+        }
+    }
+
+    // This sequence followed by 'other', which must have the same frequency
+    // table. If only one of them has site rates, the other's sites get
+    // rate 1. Either both or neither must have site classes.
+    pub fn concat(&self, other: &Sequence) -> Sequence {
+        assert!(Arc::ptr_eq(&self.freq_table, &other.freq_table) ||
+            self.freq_table == other.freq_table,
+            "Can't concatenate sequences with different frequency tables");
+
+        let mut nucleotides = self.nucleotides.clone();
+        nucleotides.extend_from_slice(&other.nucleotides);
+
+        let rates_of = |s: &Sequence| match &s.site_rates {
+            Some(r) => r.to_vec(),
+            None    => vec![1.0; s.len()]
+        };
+        let site_rates = match (&self.site_rates, &other.site_rates) {
+            (None, None) => None,
+            _            => {
+                let mut rates = rates_of(self);
+                rates.extend(rates_of(other));
+                Some(Arc::new(rates))
+            }
+        };
+
+        let site_classes = match (&self.site_classes, &other.site_classes) {
+            (None, None)       => None,
+            (Some(a), Some(b)) => Some(Arc::new([&a[..], &b[..]].concat())),
+            _                  => panic!("Can't concatenate sequences with \
+                and without site classes")
+        };
+
+        Sequence {
+            nucleotides,
+            freq_table: Arc::clone(&self.freq_table),
+            max_freq: self.max_freq,
+            site_rates,
+            site_classes
         }
     }
+
+    // Number of symbols, codons count as three
+    pub fn len(&self) -> usize {
+        self.nucleotides.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nucleotides.is_empty()
+    }
+
+    pub fn set_site_rates(&mut self, rates: Arc<Vec<f64>>) {
+        assert!(rates.len() == self.len(),
+            "Site rates don't match the sequence length");
+        self.site_rates = Some(rates);
+    }
+
+    pub fn site_rates(&self) -> Option<&Arc<Vec<f64>>> {
+        self.site_rates.as_ref()
+    }
+
+    pub fn set_site_classes(&mut self, classes: Arc<Vec<usize>>) {
+        assert!(classes.len() == self.len(),
+            "Site classes don't match the sequence length");
+        self.site_classes = Some(classes);
+    }
+
+    pub fn site_classes(&self) -> Option<&Arc<Vec<usize>>> {
+        self.site_classes.as_ref()
+    }
+
+    // Number of sites where this and 'other', of the same length, differ
+    pub fn hamming_distance(&self, other: &Sequence) -> usize {
+        hamming_distance(&self.nucleotides, &other.nucleotides)
+    }
+
+    #[allow(dead_code)]
+    pub fn print(&self) {
+        println!("{}", self.as_str());
+    }
+
+    // Sequences only ever hold ASCII symbols, which is checked on debug
+    // builds whenever symbols are added. Should a non UTF-8 byte make it in
+    // anyways, this panics rather than handing out an invalid &str.
+    pub fn as_str(&self) -> &str {
+        debug_assert!(self.nucleotides.is_ascii(),
+            "Sequence holds non ASCII symbols");
+        std::str::from_utf8(&self.nucleotides)
+            .expect("Sequence holds non UTF-8 symbols")
+    }
+
+    // Fallible version of 'as_str', copying the sequence out
+    pub fn try_to_string(&self) -> Result<String, std::str::Utf8Error> {
+        std::str::from_utf8(&self.nucleotides).map(String::from)
+    }
 }
@@ -0,0 +1,141 @@
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result, Write};
+
+// Symbol counts of one or more sequences
+#[derive(Clone, Default)]
+pub struct Composition {
+    counts: BTreeMap<u8, usize>,
+    total: usize
+}
+
+impl Composition {
+    pub fn of(s: &str) -> Composition {
+        let mut comp = Composition::default();
+        for &b in s.as_bytes() {
+            *comp.counts.entry(b).or_insert(0) += 1;
+        }
+        comp.total = s.len();
+
+        comp
+    }
+
+    fn add(&mut self, other: &Composition) {
+        for (&b, &n) in &other.counts {
+            *self.counts.entry(b).or_insert(0) += n;
+        }
+        self.total += other.total;
+    }
+
+    pub fn count(&self, b: u8) -> usize {
+        *self.counts.get(&b).unwrap_or(&0)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn fraction(&self, b: u8) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        self.count(b) as f64 / self.total as f64
+    }
+
+    // Fraction of G and C, None if there's anything besides nucleotides
+    pub fn gc_content(&self) -> Option<f64> {
+        if self.counts.keys().any(|b| !b"AGCTU".contains(b)) {
+            return None;
+        }
+
+        Some(self.fraction(b'G') + self.fraction(b'C'))
+    }
+}
+
+// Per taxon and overall composition of 'seqs'
+pub fn composition(seqs: &[(String, String)]) ->
+    (Vec<(&str, Composition)>, Composition) {
+    let mut overall = Composition::default();
+    let per_taxon = seqs.iter()
+        .map(|(k, v)| {
+            let comp = Composition::of(v);
+            overall.add(&comp);
+            (k.as_str(), comp)
+        })
+        .collect();
+
+    (per_taxon, overall)
+}
+
+// Tab separated table with a row per taxon, in the order of 'seqs', plus an
+// overall row, giving "count (fraction)" for every symbol and GC content
+// for nucleotides, fractions rounded to 'digits' decimals
+pub fn write_report<W: Write>(out: &mut W, seqs: &[(String, String)],
+    digits: usize) -> Result<()> {
+    let (per_taxon, overall) = composition(seqs);
+    let symbols: Vec<u8> = overall.counts.keys().copied().collect();
+    let nucleotides = overall.gc_content().is_some();
+
+    write!(out, "taxon\tlength")?;
+    for &b in &symbols {
+        write!(out, "\t{}", b as char)?;
+    }
+    if nucleotides {
+        write!(out, "\tGC")?;
+    }
+    writeln!(out)?;
+
+    let rows = per_taxon.iter().map(|(k, c)| (*k, c))
+        .chain(std::iter::once(("overall", &overall)));
+    for (name, comp) in rows {
+        write!(out, "{}\t{}", name, comp.total())?;
+        for &b in &symbols {
+            write!(out, "\t{} ({:.*})", comp.count(b), digits,
+                comp.fraction(b))?;
+        }
+        if let Some(gc) = comp.gc_content() {
+            write!(out, "\t{:.*}", digits, gc)?;
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+// Number of positions where 'a' and 'b', of the same length, differ
+pub fn hamming_distance(a: &[u8], b: &[u8]) -> usize {
+    assert_eq!(a.len(), b.len(), "Hamming distance needs equal lengths");
+    a.iter().zip(b).filter(|(x, y)| x != y).count()
+}
+
+// Tab separated table with the differences between the sequence 'root' and
+// every other sequence in 'seqs', and the fraction of sites that differ.
+// Sequences not spanning as many sites as 'root' are skipped. Fractions are
+// rounded to 'digits' decimals.
+pub fn write_divergence<W: Write>(out: &mut W, seqs: &[(String, String)],
+    root: &str, digits: usize) -> Result<()> {
+    let root_seq = match seqs.iter().find(|(k, _)| k == root) {
+        Some((_, v)) => v.as_bytes(),
+        None         => return Err(Error::new(ErrorKind::NotFound,
+            format!("No sequence for root '{}'", root)))
+    };
+
+    writeln!(out, "taxon\tsites\tdifferences\tp-distance")?;
+    for (k, v) in seqs.iter().filter(|(k, _)| k != root) {
+        if v.len() != root_seq.len() {
+            warn!("Skipping divergence of '{}', it has {} sites and the \
+                root {}", k, v.len(), root_seq.len());
+            continue
+        }
+
+        let diffs = hamming_distance(v.as_bytes(), root_seq);
+        let p = if v.is_empty() { 0.0 } else {
+            diffs as f64 / v.len() as f64
+        };
+        writeln!(out, "{}\t{}\t{}\t{:.*}", k, v.len(), diffs, digits, p)?;
+    }
+
+    out.flush()
+}
@@ -1,13 +1,26 @@
+use crate::indels::IndelModel;
 use crate::sequence::Sequence;
-use crate::mutator::Mutator;
+use crate::mutator::{MutationError, Mutator};
+use crate::rates::RateHeterogeneity;
 
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use log::warn;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 struct NNode {
     children: Vec<NNode>,
     id: Option<String>,
     branch_length: f64,
-    sequence: Option<Sequence>
+    // Key/value pairs from an NHX comment, e.g. [&&NHX:S=human:B=90]
+    attributes: HashMap<String, String>,
+    sequence: Option<Sequence>,
+    // Sites that changed along the branch above in the last simulation, if
+    // substitutions were counted
+    substitutions: usize
 }
 
 impl NNode {
@@ -16,12 +29,14 @@ impl NNode {
             children: Vec::<NNode>::new(),
             id: None,
             branch_length: 0.0,
-            sequence: None
+            attributes: HashMap::new(),
+            sequence: None,
+            substitutions: 0
         }
     }
 
     fn set_id(&mut self, s: &str) {
-        if s.len() > 0 {
+        if !s.is_empty() {
             self.id = Some(String::from(s));
         } else {
             self.id = None;
@@ -32,19 +47,38 @@ impl NNode {
         self.branch_length = d;
     }
 
-    fn consume(&mut self, flag: u8, buf: &str) {
+    fn consume(&mut self, flag: u8, buf: &str) -> Result<(), String> {
         match flag {
             1 => self.set_id(buf),
             2 => {
                 let branch: f64 = match buf.parse() {
                     Ok(n)  => n,
-                    Err(_) => panic!("Could not parse \"{}\" into branch!", buf)
+                    Err(_) => return Err(format!(
+                        "Could not parse \"{}\" into branch", buf))
                 };
 
                 self.set_branch_length(branch)
             }
-            _ => assert!(false, "Invalid read flag = {}", flag)
+            _ => panic!("Invalid read flag = {}", flag)
+        }
+
+        Ok(())
+    }
+
+    // Store the "key=value" pairs of an NHX comment's body, the part after
+    // "&&NHX", which are each preceded by a ':'
+    fn add_nhx_attributes(&mut self, body: &str) -> Result<(), String> {
+        for pair in body.split(':').filter(|p| !p.is_empty()) {
+            match pair.split_once('=') {
+                Some((k, v)) if !k.is_empty() => {
+                    self.attributes.insert(k.to_string(), v.to_string());
+                },
+                _ => return Err(format!("Malformed NHX attribute \"{}\", \
+                    expected key=value", pair))
+            }
         }
+
+        Ok(())
     }
 
     fn add_child(&mut self, c: NNode) {
@@ -53,7 +87,7 @@ impl NNode {
 
     #[allow(dead_code)]
     fn print(&self, indent_lvl: usize) {
-        let indent: usize = indent_lvl * 1;
+        let indent: usize = indent_lvl;
 
         println!("{:indent$}**********************", "", indent=indent);
 
@@ -83,10 +117,240 @@ impl NNode {
     }
 }
 
+// What to do with negative branch lengths, which substitution models can't
+// make sense of
+#[derive(Clone, Copy, PartialEq)]
+pub enum NegativeBranches {
+    // Fail to build the tree
+    Error,
+    // Treat them as 0, so the child is a copy of its parent
+    Zero
+}
+
+// Random sequence of 'l' sites for a root, drawn from the model's
+// frequencies, or from 'frequencies' (in the model's alphabet order) if
+// given. Those only set the root's composition, the sequence keeps the
+// model's frequencies for anything drawn later.
+pub fn random_root(m: &dyn Mutator, l: usize, frequencies: Option<&[f64]>,
+    rng: &mut StdRng) -> Sequence {
+    let frequencies = match frequencies {
+        Some(f) => f,
+        None    => return m.random(l, rng)
+    };
+    assert!(m.site_width() == 1 && frequencies.len() == m.alphabet().len(),
+        "Root frequencies need one value per symbol of the model");
+
+    let table: Vec<(u8, f64)> = m.alphabet().iter().cloned()
+        .zip(frequencies.iter().cloned())
+        .collect();
+    let drawn = Sequence::new(&table, l, rng);
+    let mut root = Sequence::from_vec(drawn.nucleotides, m.frequencies());
+    m.prepare_root(&mut root, rng);
+    root
+}
+
+// Rates for 'sites' sites, repeated for each of the 'width' symbols of
+// a site
+fn draw_site_rates(rates: &RateHeterogeneity, sites: usize, width: usize,
+    rng: &mut StdRng) -> Option<Vec<f64>> {
+    rates.draw(sites, rng).map(|r| r.iter()
+        .flat_map(|&rate| std::iter::repeat_n(rate, width))
+        .collect())
+}
+
+// How 'dfs_evolve' treats nodes and branches
+#[derive(Clone, Copy, Default)]
+pub struct EvolveOptions {
+    // Also output the sequences of internal nodes
+    pub ancestral: bool,
+    // Branches shorter than this copy their parent's sequence unchanged
+    pub branch_min: f64,
+    // Insertions and deletions along branches, if any
+    pub indels: Option<IndelModel>,
+    // Count the sites substituted along every branch
+    pub count_substitutions: bool,
+    // Length of a branch above the root to mutate its sequence along before
+    // evolving the tree, bringing it closer to the model's equilibrium
+    pub burnin: f64
+}
+
+// Subtrees this close to the root are evolved on their own rayon tasks,
+// deeper ones are evolved sequentially
+const PARALLEL_DEPTH: usize = 8;
+
+// Evolve 'node' from 'parent_seq' (if it isn't the root) and then all of its
+// descendants, returning the sequences to output. Nodes give up their
+// sequence once their children have been mutated, so no sequences are left
+// in the subtree afterwards. Stops at the first sequence that can't be
+// mutated.
+fn evolve_subtree(node: &mut NNode, parent_seq: Option<&Sequence>,
+    m: &dyn Mutator, mut rng: StdRng, opts: EvolveOptions, depth: usize) ->
+    Result<Vec<(String, Sequence)>, MutationError> {
+    if let Some(parent_seq) = parent_seq {
+        evolve_branch(m, parent_seq, node, opts, &mut rng)?;
+    }
+
+    if depth >= PARALLEL_DEPTH || node.children.len() < 2 {
+        return evolve_sequential(node, m, rng, opts);
+    }
+
+    let seeds: Vec<u64> = node.children.iter().map(|_| rng.gen()).collect();
+    let seq = node.sequence.take().unwrap();
+    let subtrees = node.children.par_iter_mut().zip(seeds)
+        .map(|(child, seed)| evolve_subtree(child, Some(&seq), m,
+            StdRng::seed_from_u64(seed), opts, depth + 1))
+        .try_reduce(Vec::new, |mut a, b| {
+            a.extend(b);
+            Ok(a)
+        })?;
+
+    let mut out = Vec::<(String, Sequence)>::new();
+    if let Some(id) = output_id(node, opts.ancestral) {
+        out.push((id, seq));
+    }
+    out.extend(subtrees);
+    Ok(out)
+}
+
+// Same as 'evolve_subtree', once 'node' has its sequence, without spawning
+// any tasks. Iterative so deep trees don't overflow the stack.
+fn evolve_sequential(node: &mut NNode, m: &dyn Mutator, rng: StdRng,
+    opts: EvolveOptions) -> Result<Vec<(String, Sequence)>, MutationError> {
+    let mut out = Vec::<(String, Sequence)>::new();
+    let mut stack = vec![(node, rng)];
+
+    while let Some((curr_node, mut curr_rng)) = stack.pop() {
+        let id = output_id(curr_node, opts.ancestral);
+        let seq = curr_node.sequence.take().unwrap();
+        for child in &mut curr_node.children {
+            let mut child_rng = StdRng::seed_from_u64(curr_rng.gen());
+            evolve_branch(m, &seq, child, opts, &mut child_rng)?;
+            stack.push((child, child_rng));
+        }
+
+        // Children have their sequences, this one goes to the output or
+        // is freed
+        if let Some(id) = id {
+            out.push((id, seq));
+        }
+    }
+
+    Ok(out)
+}
+
+// Give 'node' the sequence at the end of its branch, starting at
+// 'parent_seq'. Branches shorter than 'branch_min' are too short to be worth
+// mutating along, the parent's sequence is copied instead. Indels, if any,
+// happen after the substitutions, which are counted before them.
+fn evolve_branch(m: &dyn Mutator, parent_seq: &Sequence, node: &mut NNode,
+    opts: EvolveOptions, rng: &mut StdRng) -> Result<(), MutationError> {
+    let v = node.branch_length;
+    node.substitutions = 0;
+    if v < opts.branch_min {
+        node.sequence = Some(parent_seq.clone());
+        return Ok(());
+    }
+
+    let mut seq = m.mutate(parent_seq, v, rng)?;
+    if opts.count_substitutions {
+        node.substitutions = changed_sites(parent_seq, &seq, m.site_width());
+    }
+    if let Some(indels) = &opts.indels {
+        indels.evolve(&mut seq, v, m, rng);
+    }
+
+    node.sequence = Some(seq);
+    Ok(())
+}
+
+// Sites of 'width' symbols that differ between 'a' and 'b'
+fn changed_sites(a: &Sequence, b: &Sequence, width: usize) -> usize {
+    a.nucleotides.chunks(width).zip(b.nucleotides.chunks(width))
+        .filter(|(x, y)| x != y)
+        .count()
+}
+
+// Id to output 'node's sequence under, if it's a tip or 'ancestral' is set
+fn output_id(node: &NNode, ancestral: bool) -> Option<String> {
+    if node.children.is_empty() {
+        assert!(node.id.is_some(), "Tip nodes must be named, or given
+            automatic ids with label_tips");
+    } else if ancestral {
+        assert!(node.id.is_some(), "Internal nodes must be labeled to
+            output ancestral sequences");
+    } else {
+        return None;
+    }
+
+    node.id.clone()
+}
+
+// First label shared by two tips under 'root'. Tips are output by label, a
+// repeated one would overwrite the other's sequence.
+fn repeated_tip(root: &NNode) -> Option<&str> {
+    let mut tips = HashSet::<&str>::new();
+    let mut stack: Vec<&NNode> = vec![root];
+    while let Some(node) = stack.pop() {
+        if let (true, Some(id)) = (node.children.is_empty(), &node.id) {
+            if !tips.insert(id) {
+                return Some(id);
+            }
+        }
+        stack.extend(node.children.iter());
+    }
+
+    None
+}
+
+// Pieces of a Newick string still to be written, nodes are opened before
+// their children and closed after them
+enum NewickStep<'a> {
+    Open(&'a NNode),
+    Comma,
+    Close(&'a NNode)
+}
+
+// Append 'node's label, branch length (unless it's the root) and NHX
+// attributes to 'newick'. Labels that would be misread are quoted. Branch
+// lengths are rounded to 'digits' decimals if given.
+fn push_newick_label(newick: &mut String, node: &NNode, is_root: bool,
+    digits: Option<usize>) {
+    if let Some(id) = &node.id {
+        if id.chars().any(|c| c.is_whitespace() || "()[]':;,".contains(c)) {
+            newick.push_str(&format!("'{}'", id.replace('\'', "''")));
+        } else {
+            newick.push_str(id);
+        }
+    }
+
+    if !is_root {
+        match digits {
+            Some(d) => newick.push_str(&format!(":{:.*}", d,
+                node.branch_length)),
+            None    => newick.push_str(&format!(":{}", node.branch_length))
+        }
+    }
+
+    if !node.attributes.is_empty() {
+        let mut keys: Vec<&String> = node.attributes.keys().collect();
+        keys.sort();
+
+        newick.push_str("[&&NHX");
+        for k in keys {
+            newick.push_str(&format!(":{}={}", k, node.attributes[k]));
+        }
+        newick.push(']');
+    }
+}
+
 pub struct NTree {
     root: Option<NNode>,
     size: usize,
+    // Negative branch lengths that were set to 0 while building
+    zeroed_branches: usize,
     partition: usize,
+    // Per symbol rates of the last simulation, None if they were all 1
+    site_rates: Option<Arc<Vec<f64>>>,
     build_str: String
 }
 
@@ -95,17 +359,23 @@ impl NTree {
         NTree {
             root: None,
             size: 0,
+            zeroed_branches: 0,
             partition: p,
+            site_rates: None,
             build_str: s
         }
     }
 
-    pub fn build_from_newick(&mut self) {
+    // Build the tree from its Newick string, describing what's wrong with
+    // the string on failure. Negative branch lengths are handled according
+    // to 'negative'.
+    pub fn build_from_newick(&mut self, negative: NegativeBranches) ->
+        Result<(), String> {
         assert!(self.root.is_none(), "Tree already built!");
 
         // Iterate over all chars, we'll use a stack to keep track of parent
         // nodes, and build the tree depth-first as we read new nodes.
-        let mut iter  = self.build_str.chars();
+        let mut iter  = self.build_str.chars().peekable();
         let mut stack = Vec::<NNode>::new();
 
         // String buffer to parse ids and branch lengths
@@ -117,6 +387,9 @@ impl NTree {
         let mut read_flag: u8 = 1;
         // Flag that indicates if we're done reading
         let mut break_bool    = false;
+        // Flag that indicates whitespace came after what's in the buffer,
+        // which must then be complete
+        let mut spaced        = false;
 
         // Node we're currently building, we'll start with the root
         let mut curr_node = NNode::new_empty();
@@ -132,13 +405,19 @@ impl NTree {
             } else if c == ',' || c == ')' {
                 // Assert that there's a parent node to add to
                 let stk_len = stack.len();
-                assert!(stk_len > 0, "Empty tree building stack, does your
-                    Newick tree have a single root node?");
+                if stk_len == 0 && c == ')' {
+                    return Err("Unbalanced parens on Newick tree, extra ')'"
+                        .to_string());
+                } else if stk_len == 0 {
+                    return Err("Node outside of the root on Newick tree, is \
+                        there an extra ')' or a missing '('?".to_string());
+                }
 
                 // Finish reading the current node
-                curr_node.consume(read_flag, &mut buffer.trim());
+                curr_node.consume(read_flag, &buffer)?;
                 buffer.clear();
                 read_flag = 1;
+                spaced = false;
 
                 // Finally, add the newly finished node to the its parent
                 stack[stk_len - 1].add_child(curr_node);
@@ -153,16 +432,61 @@ impl NTree {
                 }
             // A colon delimits id and branch length
             } else if c == ':' {
-                curr_node.consume(read_flag, &mut buffer.trim());
+                curr_node.consume(read_flag, &buffer)?;
                 buffer.clear();
                 read_flag = 2;
+                spaced = false;
             // Colon marks end of newick tree
             } else if c == ';'{
-                curr_node.consume(read_flag, &mut buffer.trim());
+                curr_node.consume(read_flag, &buffer)?;
                 buffer.clear();
                 break_bool = true;
+            // Square brackets delimit comments (e.g. [&rate=0.3]), NHX ones
+            // annotate the node being read, anything else is skipped
+            } else if c == '[' {
+                let mut comment = String::new();
+                loop {
+                    match iter.next() {
+                        Some(']') => break,
+                        Some(x)   => comment.push(x),
+                        None      => return Err("Unterminated '[' comment \
+                            in Newick tree".to_string())
+                    }
+                }
+
+                if let Some(body) = comment.strip_prefix("&&NHX") {
+                    curr_node.add_nhx_attributes(body)?;
+                }
+            // Single quotes delimit a label read verbatim, delimiters and
+            // all, with '' standing for a literal quote
+            } else if c == '\'' {
+                loop {
+                    match iter.next() {
+                        Some('\'') => {
+                            if iter.peek() == Some(&'\'') {
+                                iter.next();
+                                buffer.push('\'');
+                            } else {
+                                break;
+                            }
+                        },
+                        Some(x) => buffer.push(x),
+                        None    => return Err("Unterminated quoted label \
+                            in Newick tree".to_string())
+                    }
+                }
+
+            // Whitespace (newlines included) outside quoted labels only
+            // separates tokens, so it can't be inside an id or branch length
+            } else if c.is_whitespace() {
+                spaced = !buffer.is_empty();
             // Else, we're reading an id or branch length, put in buffer
             } else {
+                if spaced {
+                    return Err(format!("Whitespace after '{}' inside an \
+                        unquoted label or branch length, quote labels with \
+                        spaces", buffer));
+                }
                 buffer.push(c);
             }
 
@@ -175,68 +499,460 @@ impl NTree {
         }
 
         if c_o.is_some() {
-            println!("Newick tree string included characters after
-                ';' character. Ignoring...");
+            warn!("Newick tree string included characters after ';' \
+                character. Ignoring...");
         }
 
         // Assert that the tree was paren balanced (no nodes left on stack)
-        assert!(stack.len() == 0, "Unbalanced parens on Newick tree");
+        if !break_bool {
+            return Err("Newick tree is missing its trailing ';'".to_string());
+        }
+
+        // Check that the tree was paren balanced (no nodes left on stack)
+        if !stack.is_empty() {
+            return Err(format!("Unbalanced parens on Newick tree, missing \
+                {} ')'", stack.len()));
+        }
+
+        // A lone ';' leaves us with a root that has neither id nor children
+        if curr_node.children.is_empty() && curr_node.id.is_none() {
+            return Err("Empty Newick tree".to_string());
+        }
+        // Deal with negative branches, the root's doesn't matter
+        let mut stack: Vec<&mut NNode> = curr_node.children.iter_mut()
+            .collect();
+        while let Some(node) = stack.pop() {
+            if node.branch_length < 0.0 {
+                if negative == NegativeBranches::Error {
+                    return Err(format!("Negative branch length {}",
+                        node.branch_length));
+                }
+
+                node.branch_length = 0.0;
+                self.zeroed_branches += 1;
+            }
+            stack.extend(node.children.iter_mut());
+        }
+
+        if let Some(id) = repeated_tip(&curr_node) {
+            return Err(format!("Tip label '{}' appears more than once", id));
+        }
+
         self.root = Some(curr_node);
         self.size += 1;
 
         // Cleanup
         self.build_str = String::new();
+
+        Ok(())
     }
 
-    pub fn dfs_evolve(&mut self, m: &dyn Mutator,
-        h: &mut HashMap<String, Sequence>) {
-        let mut curr_node = match &mut self.root {
+    // Give every unnamed internal node a "node_N" id, N being the node's
+    // position among internal nodes in a preorder traversal (root is 0)
+    pub fn label_internal_nodes(&mut self) {
+        let root = match &mut self.root {
             Some(root_node) => root_node,
-            None            => panic!("Can't evolve an empty tree")
+            None            => panic!("Can't label an empty tree")
         };
 
-        // Simple DFS, mutating ancestral as we advance through the tree
-        let mut stack = Vec::<(&mut NNode, Option<&Sequence>)>::new();
-        stack.push((curr_node, None));
+        let mut counter: usize = 0;
+        let mut stack = vec![root];
 
-        while !stack.is_empty() {
-            let tuple = stack.pop().unwrap();
-            curr_node = tuple.0;
-            let parent_seq = tuple.1;
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() {
+                continue
+            }
 
-            // Build sequence for this node if it doesn't exist
-            if parent_seq.is_some() {
-                let mutated = m.mutate(parent_seq.unwrap(),
-                    curr_node.branch_length);
-                curr_node.sequence = Some(mutated);
-            } else {
-                assert!(curr_node.sequence.is_some(), "Can't evolve a tree
-                    with no ancestral sequence");
+            if node.id.is_none() {
+                node.id = Some(format!("node_{}", counter));
             }
+            counter += 1;
 
-            // If no children, we reached a tip node and can add to result
-            if curr_node.children.is_empty() {
-                assert!(curr_node.id.is_some(), "Currently, only named tip
-                    nodes are supported for evolution");
-                h.insert((&curr_node.id.as_ref().unwrap()).to_string(),
-                    curr_node.sequence.as_ref().unwrap().clone());
+            // Push in reverse so children are visited left to right
+            for child in node.children.iter_mut().rev() {
+                stack.push(child);
+            }
+        }
+    }
+
+    // Longest root to tip path, the root's own branch length isn't counted
+    pub fn height(&self) -> f64 {
+        self.tip_depths().into_iter().fold(0.0, f64::max)
+    }
+
+    // Whether every tip is the same distance from the root, give or take
+    // 'tolerance', as in trees from a coalescent or under a molecular clock
+    pub fn is_ultrametric(&self, tolerance: f64) -> bool {
+        let depths = self.tip_depths();
+        let min = depths.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = depths.iter().cloned().fold(0.0, f64::max);
+
+        max - min <= tolerance
+    }
+
+    // Distance from the root to every tip
+    fn tip_depths(&self) -> Vec<f64> {
+        let root = match &self.root {
+            Some(root_node) => root_node,
+            None            => panic!("Can't measure an empty tree")
+        };
+
+        let mut depths = Vec::<f64>::new();
+        let mut stack = vec![(root, 0.0)];
+
+        while let Some((node, depth)) = stack.pop() {
+            if node.children.is_empty() {
+                depths.push(depth);
+            }
+
+            for child in &node.children {
+                stack.push((child, depth + child.branch_length));
+            }
+        }
+
+        depths
+    }
+
+    // Sum of all branch lengths, besides the root's
+    pub fn total_length(&self) -> f64 {
+        let root = match &self.root {
+            Some(root_node) => root_node,
+            None            => panic!("Can't measure an empty tree")
+        };
+
+        let mut total: f64 = 0.0;
+        let mut stack = vec![root];
+
+        while let Some(node) = stack.pop() {
+            for child in &node.children {
+                total += child.branch_length;
+                stack.push(child);
+            }
+        }
+
+        total
+    }
+
+    pub fn n_tips(&self) -> usize {
+        let root = match &self.root {
+            Some(root_node) => root_node,
+            None            => panic!("Can't count tips of an empty tree")
+        };
+
+        let mut tips: usize = 0;
+        let mut stack = vec![root];
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() {
+                tips += 1;
+            }
+            stack.extend(node.children.iter());
+        }
+
+        tips
+    }
+
+    // Number of tips without an id, which can't be output
+    pub fn unnamed_tips(&self) -> usize {
+        let mut unnamed: usize = 0;
+        let mut stack: Vec<&NNode> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() && node.id.is_none() {
+                unnamed += 1;
+            }
+            stack.extend(node.children.iter());
+        }
+
+        unnamed
+    }
+
+    // First id, in preorder, given to more than one node. Tips can't share
+    // one, but internal nodes can share theirs with any node, and would
+    // overwrite each other's sequences when ancestral ones are output.
+    pub fn repeated_id(&self) -> Option<&str> {
+        let mut seen = HashSet::<&str>::new();
+        let mut stack: Vec<&NNode> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            if let Some(id) = &node.id {
+                if !seen.insert(id) {
+                    return Some(id);
+                }
+            }
+            // Push in reverse so children are visited left to right
+            stack.extend(node.children.iter().rev());
+        }
+
+        None
+    }
+
+    // Number of internal nodes without exactly two children, polytomies and
+    // nodes with a single child
+    pub fn non_binary_nodes(&self) -> usize {
+        let mut non_binary: usize = 0;
+        let mut stack: Vec<&NNode> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            if !node.children.is_empty() && node.children.len() != 2 {
+                non_binary += 1;
+            }
+            stack.extend(node.children.iter());
+        }
+
+        non_binary
+    }
+
+    // Number of tips whose branch is shorter than 'min', which evolve
+    // sequences identical to their parent's for a 'min' of 0 or less
+    pub fn short_tip_branches(&self, min: f64) -> usize {
+        let mut short: usize = 0;
+        let mut stack: Vec<&NNode> = self.root.iter()
+            .flat_map(|r| r.children.iter()).collect();
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() && node.branch_length < min {
+                short += 1;
+            }
+            stack.extend(node.children.iter());
+        }
+
+        short
+    }
+
+    // Whether every internal node, the root included, has two children
+    pub fn is_binary(&self) -> bool {
+        self.non_binary_nodes() == 0
+    }
+
+    // Split every node with more than two children into bifurcations joined
+    // by zero length branches, pairing its last two children until only two
+    // are left. Returns the number of polytomies resolved.
+    pub fn resolve_polytomies(&mut self) -> usize {
+        let mut resolved: usize = 0;
+        let mut added: usize = 0;
+        let mut stack: Vec<&mut NNode> = self.root.iter_mut().collect();
+
+        while let Some(node) = stack.pop() {
+            if node.children.len() > 2 {
+                resolved += 1;
+            }
+
+            while node.children.len() > 2 {
+                let second = node.children.pop().unwrap();
+                let first = node.children.pop().unwrap();
+
+                let mut joined = NNode::new_empty();
+                joined.add_child(first);
+                joined.add_child(second);
+                node.add_child(joined);
+                added += 1;
+            }
+
+            stack.extend(node.children.iter_mut());
+        }
+
+        self.size += added;
+        resolved
+    }
+
+    // Scale every branch so the tree's height (see 'height', which is the
+    // deepest tip for non ultrametric trees) becomes 'target'
+    pub fn rescale_to_height(&mut self, target: f64) -> Result<(), String> {
+        if target.is_nan() || target < 0.0 {
+            return Err(format!("Can't rescale a tree to height {}", target));
+        }
+
+        let height = self.height();
+        if height <= 0.0 {
+            return Err("Can't rescale a tree of height 0".to_string());
+        }
+
+        let factor = target / height;
+        let mut stack = vec![self.root.as_mut().unwrap()];
+        while let Some(node) = stack.pop() {
+            node.branch_length *= factor;
+            for child in node.children.iter_mut() {
+                stack.push(child);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Give every unnamed tip a "tip_N" id, N being the tip's 1-based
+    // position among tips in a preorder traversal. Trees sharing a topology
+    // thus get the same ids, tips of differing topologies may not match.
+    pub fn label_tips(&mut self) {
+        let root = match &mut self.root {
+            Some(root_node) => root_node,
+            None            => panic!("Can't label an empty tree")
+        };
+
+        let mut counter: usize = 0;
+        let mut stack = vec![root];
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() {
+                counter += 1;
+                if node.id.is_none() {
+                    node.id = Some(format!("tip_{}", counter));
+                }
                 continue
             }
 
-            // Push all children with parent sequence (curr's sequence)
-            for child in &mut curr_node.children {
-                stack.push((child, curr_node.sequence.as_ref()));
+            // Push in reverse so children are visited left to right
+            for child in node.children.iter_mut().rev() {
+                stack.push(child);
             }
         }
     }
 
-    pub fn create_ancestral(&mut self, m: &dyn Mutator) {
+    // Rename tips whose id is a key of 'table' to its value, as NEXUS
+    // TRANSLATE tables do. Fails if that leaves two tips with one name.
+    pub fn translate_tips(&mut self, table: &HashMap<String, String>) ->
+        Result<(), String> {
+        let mut stack: Vec<&mut NNode> = self.root.iter_mut().collect();
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() {
+                let name = node.id.as_ref().and_then(|id| table.get(id))
+                    .cloned();
+                if name.is_some() {
+                    node.id = name;
+                }
+            }
+            stack.extend(node.children.iter_mut());
+        }
+
+        match self.root.as_ref().and_then(repeated_tip) {
+            Some(id) => Err(format!("Tip label '{}' appears more than once \
+                after translating", id)),
+            None     => Ok(())
+        }
+    }
+
+    // Ids of the nodes 'dfs_evolve' outputs sequences for, in preorder
+    pub fn output_ids(&self, ancestral: bool) -> Vec<String> {
+        let mut ids = Vec::<String>::new();
+        let mut stack: Vec<&NNode> = self.root.iter().collect();
+
+        while let Some(node) = stack.pop() {
+            if node.children.is_empty() || ancestral {
+                ids.extend(node.id.clone());
+            }
+
+            // Push in reverse so children are visited left to right
+            stack.extend(node.children.iter().rev());
+        }
+
+        ids
+    }
+
+    // Evolve the ancestral sequence down the tree, storing every tip's
+    // sequence in 'h' (and internal nodes' sequences if 'opts.ancestral' is
+    // set). The root's sequence is first mutated along 'opts.burnin'.
+    // Sequences are moved into 'h' or freed as soon as they're no longer
+    // needed, leaving the tree ready for another ancestral. Fails if a
+    // sequence has symbols the model can't mutate.
+    // Subtrees near the root are evolved in parallel. Every node gets its
+    // own RNG, seeded from its parent's, so results only depend on 'rng'
+    // and not on how subtrees are scheduled.
+    pub fn dfs_evolve(&mut self, m: &dyn Mutator,
+        h: &mut HashMap<String, Sequence>, rng: &mut StdRng,
+        opts: EvolveOptions) -> Result<(), MutationError> {
+        let root = match &mut self.root {
+            Some(root_node) => root_node,
+            None            => panic!("Can't evolve an empty tree")
+        };
+
+        assert!(root.sequence.is_some(), "Can't evolve a tree with no
+            ancestral sequence");
+
+        let root_rng = StdRng::seed_from_u64(rng.gen());
+        if opts.burnin > 0.0 {
+            let seq = root.sequence.take().unwrap();
+            root.sequence = Some(m.mutate(&seq, opts.burnin, rng)?);
+        }
+
+        h.extend(evolve_subtree(root, None, m, root_rng, opts, 0)?);
+        Ok(())
+    }
+
+    // Give the root a random sequence. The root may have any number of
+    // children (e.g. the trifurcating root of an unrooted tree), they all
+    // evolve from this sequence. A branch length on the root itself is
+    // parsed but ignored, there's nothing above it to evolve from. See
+    // 'random_root' for 'root_frequencies'.
+    pub fn create_ancestral(&mut self, m: &dyn Mutator,
+        rates: &RateHeterogeneity, root_frequencies: Option<&[f64]>,
+        rng: &mut StdRng) {
         let root = match &mut self.root {
             Some(r) => r,
             None    => panic!("Can't create ancestral for an empty tree")
         };
 
-        root.sequence = Some(m.random(self.partition));
+        let mut ancestral = random_root(m, self.partition, root_frequencies,
+            rng);
+
+        // Site rates are drawn once here and inherited by every descendant
+        let site_rates = draw_site_rates(rates, self.partition,
+            m.site_width(), rng).map(Arc::new);
+        if let Some(site_rates) = &site_rates {
+            ancestral.set_site_rates(Arc::clone(site_rates));
+        }
+
+        root.sequence = Some(ancestral);
+        self.site_rates = site_rates;
+    }
+
+    // Use 's' as the root's sequence instead of a random one, site rates
+    // are still drawn as usual. Ambiguity codes in 's' are resolved
+    // according to the model's frequencies.
+    pub fn set_ancestral(&mut self, m: &dyn Mutator, mut s: Sequence,
+        rates: &RateHeterogeneity, rng: &mut StdRng) {
+        assert!(s.len() == self.partition * m.site_width(),
+            "Ancestral sequence length doesn't match the tree's partition");
+        let root = match &mut self.root {
+            Some(r) => r,
+            None    => panic!("Can't set the ancestral of an empty tree")
+        };
+
+        s.resolve_ambiguities(m.frequencies(), rng);
+        m.prepare_root(&mut s, rng);
+
+        let site_rates = draw_site_rates(rates, self.partition,
+            m.site_width(), rng).map(Arc::new);
+        if let Some(site_rates) = &site_rates {
+            s.set_site_rates(Arc::clone(site_rates));
+        }
+
+        root.sequence = Some(s);
+        self.site_rates = site_rates;
+    }
+
+    // Id (if any), branch length and substituted sites of every node but the
+    // root, in preorder, as counted by the last simulation
+    pub fn branch_substitutions(&self) -> Vec<(Option<&str>, f64, usize)> {
+        let mut branches = Vec::new();
+        let mut stack: Vec<&NNode> = self.root.iter()
+            .flat_map(|r| r.children.iter().rev())
+            .collect();
+
+        while let Some(node) = stack.pop() {
+            branches.push((node.id.as_deref(), node.branch_length,
+                node.substitutions));
+            stack.extend(node.children.iter().rev());
+        }
+
+        branches
+    }
+
+    // Rate of each symbol of the root's sequence in the last simulation,
+    // codons repeating theirs three times. None if every site evolved at
+    // rate 1, or the tree hasn't been simulated.
+    pub fn site_rates(&self) -> Option<&[f64]> {
+        self.site_rates.as_ref().map(|r| r.as_slice())
     }
 
     #[allow(dead_code)]
@@ -247,6 +963,87 @@ impl NTree {
         }
     }
 
+    // Newick string the tree is built from, empty once it's been built
+    pub fn newick(&self) -> &str {
+        &self.build_str
+    }
+
+    // Newick string of the tree as it is now, with any labels given since
+    // it was parsed. Branch lengths are written for every node but the
+    // root, exactly enough to parse back to the same value. NHX attributes
+    // are written back as NHX comments.
+    pub fn to_newick(&self) -> String {
+        self.newick_string(None)
+    }
+
+    // Same as 'to_newick', with branch lengths rounded to 'digits' decimals
+    pub fn to_newick_with_precision(&self, digits: usize) -> String {
+        self.newick_string(Some(digits))
+    }
+
+    fn newick_string(&self, digits: Option<usize>) -> String {
+        let root = match &self.root {
+            Some(r) => r,
+            None    => panic!("Can't write an empty tree")
+        };
+
+        let mut newick = String::new();
+        let mut stack = vec![NewickStep::Open(root)];
+        while let Some(step) = stack.pop() {
+            match step {
+                NewickStep::Open(node) if node.children.is_empty() => {
+                    push_newick_label(&mut newick, node,
+                        std::ptr::eq(node, root), digits);
+                },
+                NewickStep::Open(node) => {
+                    newick.push('(');
+                    stack.push(NewickStep::Close(node));
+                    // Push in reverse so children are written left to right
+                    for (i, child) in node.children.iter().enumerate().rev() {
+                        stack.push(NewickStep::Open(child));
+                        if i > 0 {
+                            stack.push(NewickStep::Comma);
+                        }
+                    }
+                },
+                NewickStep::Comma => newick.push(','),
+                NewickStep::Close(node) => {
+                    newick.push(')');
+                    push_newick_label(&mut newick, node,
+                        std::ptr::eq(node, root), digits);
+                }
+            }
+        }
+
+        newick.push(';');
+        newick
+    }
+
+    // Id of the root, if it has one
+    pub fn root_id(&self) -> Option<&str> {
+        self.root.as_ref().and_then(|r| r.id.as_deref())
+    }
+
+    // NHX attributes of the node with id 'id', None if there's no such
+    // node. Nodes labeled by 'label_internal_nodes' or 'label_tips' can be
+    // looked up by their new ids.
+    pub fn attributes(&self, id: &str) -> Option<&HashMap<String, String>> {
+        let mut stack: Vec<&NNode> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            if node.id.as_deref() == Some(id) {
+                return Some(&node.attributes);
+            }
+            stack.extend(node.children.iter());
+        }
+
+        None
+    }
+
+    // Number of negative branch lengths set to 0 while building
+    pub fn zeroed_branches(&self) -> usize {
+        self.zeroed_branches
+    }
+
     #[allow(dead_code)]
     pub fn get_size(&self) -> usize {
         self.size
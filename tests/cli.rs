@@ -0,0 +1,363 @@
+mod common;
+
+use common::data;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn trees_are_read_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aminosim"))
+        .args(["-t", "-", "-p", &data("two_trees.partitions"), "-o", "-",
+            "-m", "hky", "--kappa", "2", "--seed", "42", "-q"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let trees = std::fs::read(data("two_trees.nwk")).unwrap();
+    child.stdin.take().unwrap().write_all(&trees).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    // Same sequences as simulating from the file itself
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+        "A CGAAACGTAAAG\nB GCAAACGTAAAG\nC CGAAACGCAAAG\n");
+}
+
+#[test]
+fn model_params_have_the_given_frequencies() {
+    let out = format!("{}/model_params.fa", env!("CARGO_TARGET_TMPDIR"));
+    let output = Command::new(env!("CARGO_BIN_EXE_aminosim"))
+        .args(["-t", &data("two_trees.nwk"), "-p",
+            &data("two_trees.partitions"), "-o", &out, "-m", "hky", "--freqs",
+            "0.1,0.2,0.3,0.4", "--kappa", "3", "--seed", "42", "-q",
+            "--model-params-out", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let params = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = params.lines().collect();
+    assert!(lines.contains(&"seed = 42"), "{}", params);
+    assert!(lines.contains(&"name = \"HKY\""), "{}", params);
+    assert!(lines.contains(&"kappa = 3"), "{}", params);
+    assert!(lines.contains(&"frequencies = [0.1, 0.2, 0.3, 0.4]"), "{}",
+        params);
+}
+
+// Run aminosim with 'args', which must succeed
+fn run(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_aminosim"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}",
+        String::from_utf8_lossy(&output.stderr));
+}
+
+// Run aminosim expecting it to report an input error, returning its stderr
+fn run_failing(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_aminosim"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    stderr
+}
+
+fn tmp(name: &str) -> String {
+    format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name)
+}
+
+#[test]
+fn same_seed_gives_identical_files() {
+    let (first, second) = (tmp("seed_1.txt"), tmp("seed_2.txt"));
+    for out in [&first, &second].iter() {
+        run(&["-t", &data("two_trees.nwk"), "-p",
+            &data("two_trees.partitions"), "-o", out, "--seed", "7", "-q"]);
+    }
+
+    assert_eq!(std::fs::read(&first).unwrap(),
+        std::fs::read(&second).unwrap());
+}
+
+// Taxon ids of a phylip-like file
+fn taxa(path: &str) -> Vec<String> {
+    std::fs::read_to_string(path).unwrap().lines()
+        .map(|l| l.split(' ').next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn replicates_differ_but_share_taxa() {
+    let out = tmp("replicates.txt");
+    run(&["-t", &data("two_trees.nwk"), "-p", &data("two_trees.partitions"),
+        "-o", &out, "--seed", "3", "--replicates", "2", "-q"]);
+
+    let (first, second) = (format!("{}.1", out), format!("{}.2", out));
+    assert_ne!(std::fs::read(&first).unwrap(),
+        std::fs::read(&second).unwrap());
+    assert_eq!(taxa(&first), ["A", "B", "C"]);
+    assert_eq!(taxa(&first), taxa(&second));
+}
+
+#[test]
+fn taxa_are_written_in_a_stable_order() {
+    let out = tmp("sorted.txt");
+    for seed in ["1", "2"].iter() {
+        run(&["-t", &data("unsorted.nwk"), "-l", "10", "-o", &out, "--seed",
+            seed, "-q"]);
+        assert_eq!(taxa(&out), ["Alpha", "Mid", "Zeta"]);
+
+        run(&["-t", &data("unsorted.nwk"), "-l", "10", "-o", &out, "--seed",
+            seed, "-q", "--sort-taxa", "tree-order"]);
+        assert_eq!(taxa(&out), ["Zeta", "Alpha", "Mid"]);
+    }
+}
+
+// Newick string of a balanced tree with 2^depth tips named T0, T1...
+fn balanced_newick(depth: u32) -> String {
+    fn subtree(depth: u32, next: &mut usize) -> String {
+        if depth == 0 {
+            *next += 1;
+            return format!("T{}:0.05", *next - 1);
+        }
+        let left = subtree(depth - 1, next);
+        let right = subtree(depth - 1, next);
+        format!("({},{}):0.02", left, right)
+    }
+
+    format!("{};", subtree(depth, &mut 0))
+}
+
+#[test]
+fn thread_count_does_not_change_the_output() {
+    let tree = tmp("threads.nwk");
+    std::fs::write(&tree, balanced_newick(7)).unwrap();
+
+    let (one, four) = (tmp("threads_1.txt"), tmp("threads_4.txt"));
+    for (out, threads) in [(&one, "1"), (&four, "4")].iter() {
+        run(&["-t", &tree, "-l", "200", "-m", "hky", "--gamma", "0.5",
+            "--ancestral", "-o", out, "--seed", "11", "--threads", threads,
+            "-q"]);
+    }
+
+    assert_eq!(std::fs::read(&one).unwrap(), std::fs::read(&four).unwrap());
+}
+
+// Fraction of sites where the first two sequences of a phylip-like file
+// differ
+fn p_distance(path: &str) -> f64 {
+    let text = std::fs::read_to_string(path).unwrap();
+    let seqs: Vec<&str> = text.lines()
+        .map(|l| l.split(' ').nth(1).unwrap())
+        .collect();
+    let diffs = seqs[0].bytes().zip(seqs[1].bytes())
+        .filter(|(a, b)| a != b)
+        .count();
+    diffs as f64 / seqs[0].len() as f64
+}
+
+#[test]
+fn mutation_rate_scales_divergence() {
+    let tree = tmp("mutation_rate.nwk");
+    std::fs::write(&tree, "(A:0.025,B:0.025);").unwrap();
+
+    let mut distances = Vec::new();
+    for mu in ["1", "2"].iter() {
+        let out = tmp(&format!("mutation_rate_{}.txt", mu));
+        run(&["-t", &tree, "-l", "40000", "-m", "jc69", "--scale", "2",
+            "--mutation-rate", mu, "-o", &out, "--seed", "8", "-q"]);
+        distances.push(p_distance(&out));
+    }
+
+    // Branches of 0.1 and 0.2 substitutions per site in total
+    let jc69 = |v: f64| 0.75 * (1.0 - (-4.0_f64 / 3.0 * v).exp());
+    assert!((distances[0] - jc69(0.1)).abs() < 0.01, "{}", distances[0]);
+    assert!((distances[1] - jc69(0.2)).abs() < 0.01, "{}", distances[1]);
+    let ratio = distances[1] / distances[0];
+    assert!(ratio > 1.8 && ratio < 2.1, "{}", ratio);
+}
+
+#[test]
+fn charsets_cover_the_alignment() {
+    let (trees, parts) = (tmp("charsets.nwk"), tmp("charsets.partitions"));
+    std::fs::write(&trees, "(A:0.1,B:0.2);\n(A:0.3,B:0.1);\n(A:0.2,B:0.2);\n")
+        .unwrap();
+    std::fs::write(&parts, "4\n9\n6\n").unwrap();
+
+    let (out, charsets) = (tmp("charsets.txt"), tmp("charsets.cs"));
+    run(&["-t", &trees, "-p", &parts, "-o", &out, "--charset-file",
+        &charsets, "--seed", "1", "-q"]);
+
+    let mut next = 1;
+    let lines = std::fs::read_to_string(&charsets).unwrap();
+    for (i, line) in lines.lines().enumerate() {
+        let range = line.strip_prefix(&format!("DNA, part{} = ", i))
+            .unwrap_or_else(|| panic!("Unexpected charset '{}'", line));
+        let (start, end) = range.split_once('-').unwrap();
+        let (start, end): (usize, usize) =
+            (start.parse().unwrap(), end.parse().unwrap());
+        assert_eq!(start, next);
+        assert!(end >= start);
+        next = end + 1;
+    }
+
+    assert_eq!(lines.lines().count(), 3);
+    let width = std::fs::read_to_string(&out).unwrap().lines().next()
+        .unwrap().split(' ').nth(1).unwrap().len();
+    assert_eq!(width, 19);
+    assert_eq!(next - 1, width);
+}
+
+#[test]
+fn polytomies_fail_binary_checks_unless_resolved() {
+    let out = tmp("polytomies.txt");
+    let args = ["-t", &data("trifurcating.nwk"), "-l", "10", "-o", &out,
+        "--require-binary", "-q"];
+    assert!(run_failing(&args)
+        .contains("Tree 1 has 1 nodes without exactly two children"));
+
+    run(&[&args[..], &["--resolve-polytomies"]].concat());
+    assert_eq!(taxa(&out), ["A", "B", "C", "D", "E"]);
+}
+
+#[test]
+fn site_rates_cover_the_alignment() {
+    let (out, rates) = (tmp("rates.txt"), tmp("rates.tsv"));
+    run(&["-t", &data("two_trees.nwk"), "-p", &data("two_trees.partitions"),
+        "-o", &out, "--gamma", "0.5", "--gamma-cats", "4", "--rates-out",
+        &rates, "--seed", "2", "-q"]);
+
+    let width = std::fs::read_to_string(&out).unwrap().lines().next()
+        .unwrap().split(' ').nth(1).unwrap().len();
+    let rates = std::fs::read_to_string(&rates).unwrap();
+    let sites: Vec<&str> = rates.lines().skip(1).collect();
+    assert_eq!(sites.len(), width);
+    for (i, line) in sites.iter().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert_eq!(fields[0], (i + 1).to_string());
+        assert!(fields[2].parse::<f64>().unwrap() > 0.0);
+    }
+}
+
+#[test]
+fn replicates_of_a_subset_match_the_full_run() {
+    let (full, subset) = (tmp("replicates_full.txt"),
+        tmp("replicates_subset.txt"));
+    let args = ["-t", &data("two_trees.nwk"), "-p",
+        &data("two_trees.partitions"), "--seed", "3", "--replicates", "2",
+        "-q"];
+    run(&[&args[..], &["-o", &full]].concat());
+    run(&[&args[..], &["-o", &subset, "--trees-subset", "2-2"]].concat());
+
+    for r in 1..=2 {
+        let full = std::fs::read_to_string(format!("{}.{}", full, r))
+            .unwrap();
+        let subset = std::fs::read_to_string(format!("{}.{}", subset, r))
+            .unwrap();
+        for (f, s) in full.lines().zip(subset.lines()) {
+            let (f_id, f_seq) = f.split_once(' ').unwrap();
+            let (s_id, s_seq) = s.split_once(' ').unwrap();
+            assert_eq!(f_id, s_id);
+            assert_eq!(&f_seq[5..], s_seq, "replicate {}", r);
+        }
+    }
+}
+
+#[test]
+fn ancestral_output_rejects_repeated_internal_labels() {
+    let (tree, out) = (tmp("repeated_internal.nwk"), tmp("repeated.txt"));
+    std::fs::write(&tree, "((A:0.1,B:0.2)X:0.1,(C:0.3,D:0.4)X:0.2);\n")
+        .unwrap();
+    let args = ["-t", &tree, "-l", "10", "-o", &out, "-q"];
+
+    assert!(run_failing(&[&args[..], &["--ancestral"]].concat())
+        .contains("Tree 1 has more than one node labeled 'X'"));
+
+    // Without ancestral output only tips are written, which are unique
+    run(&args);
+    assert_eq!(taxa(&out), ["A", "B", "C", "D"]);
+}
+
+#[test]
+fn invalid_model_parameters_are_reported() {
+    // Models are checked before any tree is read
+    let missing = tmp("no_such_tree.nwk");
+    let err = run_failing(&["-t", &missing, "-l", "10", "-o",
+        &tmp("bad_model.txt"), "--freqs", "0,0,0.5,0.5", "-q"]);
+    assert!(err.starts_with("Invalid HKY parameters: Base frequencies"),
+        "{}", err);
+
+    let err = run_failing(&["-t", &missing, "-l", "10", "-o",
+        &tmp("bad_model.txt"), "-m", "tn93", "--freqs", "0.5,0,0.5,0",
+        "-q"]);
+    assert!(err.starts_with("Invalid TN93 parameters"), "{}", err);
+}
+
+#[test]
+fn rna_output_swaps_t_for_u() {
+    let (dna, rna) = (tmp("dna.txt"), tmp("rna.txt"));
+    let args = ["-t", &data("two_trees.nwk"), "-p",
+        &data("two_trees.partitions"), "--seed", "12", "-q"];
+    run(&[&args[..], &["-o", &dna]].concat());
+    run(&[&args[..], &["-o", &rna, "--rna"]].concat());
+
+    let (dna, rna) = (std::fs::read_to_string(&dna).unwrap(),
+        std::fs::read_to_string(&rna).unwrap());
+    assert!(dna.contains('T'));
+    assert!(!rna.contains('T'));
+    assert_eq!(dna.replace('T', "U"), rna);
+}
+
+#[test]
+fn divergence_without_trees_is_an_error() {
+    let tree = tmp("empty.nwk");
+    std::fs::write(&tree, "").unwrap();
+    let err = run_failing(&["-t", &tree, "-l", "10", "-o", &tmp("empty.txt"),
+        "--ancestral", "--divergence", "-q"]);
+    assert!(err.contains("No trees were found"), "{}", err);
+}
+
+#[test]
+fn inputs_are_checked_without_an_output_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aminosim"))
+        .args(["-t", &data("two_trees.nwk"), "-p",
+            &data("two_trees.partitions"), "--check"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Inputs are valid: 2 trees, 12 sites in total, 3 taxa"));
+
+    let trees = tmp("check_three.nwk");
+    std::fs::write(&trees, "(A:1,B:1);\n(A:1,B:1);\n(A:1,B:1);\n").unwrap();
+    let err = run_failing(&["-t", &trees, "-p",
+        &data("two_trees.partitions"), "--check"]);
+    assert!(err.contains("Line 3: Tree has no partition"), "{}", err);
+}
+
+#[test]
+fn split_partitions_get_a_file_each() {
+    let dir = tmp("split");
+    let _ = std::fs::remove_dir_all(&dir);
+    run(&["-t", &data("two_trees.nwk"), "-p", &data("two_trees.partitions"),
+        "--split-partitions", &dir, "-f", "fasta", "--seed", "4", "-q"]);
+
+    let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["partition_0.fasta", "partition_1.fasta"]);
+
+    for (file, width) in files.iter().zip([5, 7].iter()) {
+        let fasta = std::fs::read_to_string(format!("{}/{}", dir, file))
+            .unwrap();
+        let seqs: Vec<&str> = fasta.lines().filter(|l| !l.starts_with('>'))
+            .collect();
+        assert_eq!(seqs.len(), 3);
+        assert!(seqs.iter().all(|s| s.len() == *width), "{}", fasta);
+    }
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use aminosim::mutator::{Codon, GeneticCode, Mutator};
+use aminosim::SimulationOptions;
+use common::tree;
+
+// Codons as indices into A, G, C, T
+const TGA: [usize; 3] = [3, 1, 0];
+const AGA: [usize; 3] = [0, 1, 0];
+
+#[test]
+fn stop_codons_depend_on_the_genetic_code() {
+    assert_eq!(GeneticCode::Standard.translate(TGA), b'*');
+    assert_eq!(GeneticCode::VertebrateMitochondrial.translate(TGA), b'W');
+    assert_eq!(GeneticCode::Standard.translate(AGA), b'R');
+    assert_eq!(GeneticCode::VertebrateMitochondrial.translate(AGA), b'*');
+}
+
+#[test]
+fn codon_model_only_has_sense_codons() {
+    let freqs = [0.25; 4];
+    let bases = [b'A', b'G', b'C', b'T'];
+    let standard = Codon::new(freqs, bases, 2.0, 0.5, 1.0).unwrap();
+    let mito = Codon::with_code(freqs, bases, 2.0, 0.5,
+        GeneticCode::VertebrateMitochondrial, 1.0).unwrap();
+
+    assert_eq!(standard.n_states(), 61);
+    assert_eq!(mito.n_states(), 60);
+    assert_eq!(GeneticCode::from_id(2),
+        Some(GeneticCode::VertebrateMitochondrial));
+    assert_eq!(GeneticCode::from_id(3), None);
+}
+
+// Protein encoded by a sequence of A, G, C, T codons
+fn translate(seq: &str) -> Vec<u8> {
+    let index = |b: u8| b"AGCT".iter().position(|&x| x == b).unwrap();
+    seq.as_bytes().chunks(3)
+        .map(|c| GeneticCode::Standard.translate([index(c[0]), index(c[1]),
+            index(c[2])]))
+        .collect()
+}
+
+#[test]
+fn synonymous_only_evolution_keeps_the_protein() {
+    let model = Codon::new([0.25; 4], *b"AGCT", 2.0, 0.0, 1.0).unwrap();
+    let t = tree("((A:1,B:2)X:0.5,C:3)R;", 300);
+    let mut opts = SimulationOptions::new(12);
+    opts.ancestral = true;
+
+    let seqs = aminosim::simulate(&mut [t], &model, &opts).unwrap();
+    let protein = translate(&seqs["R"]);
+    assert_eq!(seqs["R"].len(), 900);
+    for (id, seq) in seqs.iter() {
+        assert_eq!(translate(seq), protein, "{} changed its protein", id);
+    }
+    // Codons did change, just never to another amino acid
+    assert_ne!(seqs["C"], seqs["R"]);
+}
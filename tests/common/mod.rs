@@ -0,0 +1,26 @@
+// Fixtures shared by the integration tests, each of which only uses some
+#![allow(dead_code)]
+use aminosim::mutator::{HKY, JC69};
+use aminosim::tree::{NegativeBranches, NTree};
+
+// Path of a file in tests/data
+pub fn data(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// Tree built from 'newick', with sequences of 'partition' sites
+pub fn tree(newick: &str, partition: usize) -> NTree {
+    let mut t = NTree::new(partition, newick.to_string());
+    t.build_from_newick(NegativeBranches::Error).unwrap();
+    t
+}
+
+pub fn jc69() -> JC69 {
+    JC69::new(b'A', b'G', b'C', b'T', 1.0)
+}
+
+// HKY with even frequencies and kappa 2
+pub fn hky() -> HKY {
+    HKY::new(0.25, 0.25, 0.25, 0.25, b'A', b'G', b'C', b'T', 2.0, 1.0)
+        .unwrap()
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use aminosim::indels::IndelModel;
+use aminosim::mutator::{F81, Mixture, MutationError, Mutator};
+use aminosim::sequence::Sequence;
+use aminosim::SimulationOptions;
+use common::tree;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+const BASES: [u8; 4] = [b'A', b'G', b'C', b'T'];
+
+fn f81(freqs: [f64; 4]) -> Box<dyn Mutator> {
+    Box::new(F81::new(freqs, BASES, 1.0).unwrap())
+}
+
+// Share of the sites of 'seq' with 'base', among those where 'given' is
+// 'given_base' if there's one
+fn share(seq: &str, base: u8, given: Option<(&str, u8)>) -> f64 {
+    let sites: Vec<u8> = match given {
+        Some((other, b)) => seq.bytes().zip(other.bytes())
+            .filter(|&(_, o)| o == b).map(|(s, _)| s).collect(),
+        None             => seq.bytes().collect()
+    };
+
+    sites.iter().filter(|&&s| s == base).count() as f64 / sites.len() as f64
+}
+
+fn skewed_mixture() -> Mixture {
+    Mixture::new(vec![
+        (f81([0.97, 0.01, 0.01, 0.01]), 3.0),
+        (f81([0.01, 0.01, 0.01, 0.97]), 7.0)
+    ]).unwrap()
+}
+
+#[test]
+fn sites_keep_their_component() {
+    let mixture = skewed_mixture();
+    assert!((mixture.equilibrium_frequencies()[0] - 0.298).abs() < 1e-9);
+
+    let t = tree("(A:5,B:5)R;", 20_000);
+    let mut opts = SimulationOptions::new(2);
+    opts.ancestral = true;
+    let seqs = aminosim::simulate(&mut [t], &mixture, &opts).unwrap();
+
+    // Overall composition follows the weights
+    assert!((share(&seqs["A"], b'A', None) - 0.298).abs() < 0.02);
+    assert!((share(&seqs["A"], b'T', None) - 0.682).abs() < 0.02);
+
+    // Long branches erase the root's states, but not its sites' components
+    assert!(share(&seqs["A"], b'A', Some((&seqs["R"], b'A'))) > 0.9);
+    assert!(share(&seqs["A"], b'T', Some((&seqs["R"], b'T'))) > 0.9);
+}
+
+#[test]
+fn mixture_components_must_match() {
+    let aa = Box::new(aminosim::mutator::PoissonAA::new(1.0));
+    assert!(Mixture::new(vec![(f81([0.25; 4]), 1.0), (aa, 1.0)]).is_err());
+    assert!(Mixture::new(vec![(f81([0.25; 4]), 0.0)]).is_err());
+    assert!(Mixture::new(Vec::new()).is_err());
+}
+
+#[test]
+fn sequences_without_classes_are_errors() {
+    let mixture = skewed_mixture();
+    let s = Sequence::from_str("ACGT", mixture.frequencies()).unwrap();
+    let mut rng = StdRng::seed_from_u64(1);
+    assert_eq!(mixture.mutate(&s, 0.1, &mut rng).err(),
+        Some(MutationError::MissingSiteClasses));
+
+    let mut s = s;
+    mixture.prepare_root(&mut s, &mut rng);
+    assert!(mixture.mutate(&s, 0.1, &mut rng).is_ok());
+}
+
+#[test]
+fn inserted_sites_get_components() {
+    let t = tree("((A:0.5,B:0.5):0.5,C:1)R;", 5000);
+    let mut opts = SimulationOptions::new(4);
+    opts.ancestral = true;
+    opts.indels = Some(IndelModel::new(0.2, 3.0).unwrap());
+    let seqs = aminosim::simulate(&mut [t], &skewed_mixture(), &opts)
+        .unwrap();
+
+    assert!(seqs.values().any(|s| s.len() != 5000));
+    for s in seqs.values() {
+        assert!((share(s, b'A', None) - 0.298).abs() < 0.03);
+        assert!((share(s, b'T', None) - 0.682).abs() < 0.03);
+    }
+}
@@ -0,0 +1,211 @@
+mod common;
+
+use aminosim::mutator::{CustomMatrix, F81, HKY, K80, MatrixCache,
+    MutationError, Mutator, PoissonAA, TN93};
+use aminosim::parsers;
+use aminosim::sequence::Sequence;
+use common::{data, jc69};
+
+use ndarray::{arr2, Array2};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+const BRANCH_LENGTHS: [f64; 6] = [0.0, 0.001, 0.05, 0.3, 1.0, 10.0];
+
+fn hky_with(freqs: [f64; 4], kappa: f64) -> HKY {
+    HKY::new(freqs[0], freqs[1], freqs[2], freqs[3], b'A', b'G', b'C', b'T',
+        kappa, 1.0).unwrap()
+}
+
+fn assert_close(a: &Array2<f64>, b: &Array2<f64>) {
+    assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-12),
+        "{} != {}", a, b);
+}
+
+// Every row of 'matrix' must be a probability distribution
+fn assert_stochastic(matrix: &Array2<f64>) {
+    for row in matrix.outer_iter() {
+        assert!((row.sum() - 1.0).abs() < 1e-12, "{}", row);
+        assert!(row.iter().all(|&p| p >= 0.0), "{}", row);
+    }
+}
+
+#[test]
+fn jc69_rows_sum_to_one() {
+    for &v in BRANCH_LENGTHS.iter() {
+        let matrix = jc69().transition_matrix(v);
+        assert_stochastic(&matrix);
+
+        let e = (-4.0 / 3.0 * v).exp();
+        assert!((matrix[[0, 0]] - (0.25 + 0.75 * e)).abs() < 1e-12);
+        assert!((matrix[[0, 3]] - (0.25 - 0.25 * e)).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn tn93_with_equal_kappas_is_hky() {
+    let freqs = [0.1, 0.2, 0.3, 0.4];
+    for &kappa in [0.5, 1.0, 2.0, 8.0].iter() {
+        let tn93 = TN93::new(freqs, *b"AGCT", kappa, kappa, 1.0).unwrap();
+        for &v in BRANCH_LENGTHS.iter() {
+            assert_close(&tn93.transition_matrix(v),
+                &hky_with(freqs, kappa).transition_matrix(v));
+        }
+    }
+}
+
+#[test]
+fn hky_frequencies_are_normalized() {
+    let model = hky_with([0.2502, 0.25, 0.25, 0.2499], 2.0);
+    let freqs = model.equilibrium_frequencies();
+    assert!((freqs.iter().sum::<f64>() - 1.0).abs() < 1e-15);
+    assert!(freqs[0] > freqs[1] && freqs[1] > freqs[3]);
+}
+
+#[test]
+fn invalid_hky_frequencies_are_rejected() {
+    let new = |f: [f64; 4]| HKY::new(f[0], f[1], f[2], f[3], b'A', b'G', b'C',
+        b'T', 2.0, 1.0);
+
+    let err = new([0.3, 0.3, 0.3, 0.3]).err().unwrap();
+    assert!(err.contains("must sum to 1"), "{}", err);
+    for freqs in [[0.5, 0.5, 0.0, 0.0], [0.6, 0.3, 0.2, -0.1]].iter() {
+        let err = new(*freqs).err().unwrap();
+        assert!(err.contains("must be positive"), "{}", err);
+    }
+}
+
+#[test]
+fn k80_is_hky_with_equal_frequencies() {
+    for &kappa in [0.5, 1.0, 2.0, 8.0].iter() {
+        let k80 = K80::new(b'A', b'G', b'C', b'T', kappa, 1.0).unwrap();
+        for &v in BRANCH_LENGTHS.iter() {
+            assert_close(&k80.transition_matrix(v),
+                &hky_with([0.25; 4], kappa).transition_matrix(v));
+        }
+    }
+}
+
+#[test]
+fn f81_with_equal_frequencies_is_jc69() {
+    let f81 = F81::new([0.25; 4], *b"AGCT", 1.0).unwrap();
+    for &v in BRANCH_LENGTHS.iter() {
+        assert_close(&f81.transition_matrix(v), &jc69().transition_matrix(v));
+    }
+
+    // And it's HKY with kappa = 1 otherwise
+    let freqs = [0.1, 0.2, 0.3, 0.4];
+    let f81 = F81::new(freqs, *b"AGCT", 1.0).unwrap();
+    for &v in BRANCH_LENGTHS.iter() {
+        assert_close(&f81.transition_matrix(v),
+            &hky_with(freqs, 1.0).transition_matrix(v));
+    }
+}
+
+#[test]
+fn sampler_handles_three_states() {
+    let q = arr2(&[[-2.0, 1.0, 1.0],
+                   [0.5, -1.5, 1.0],
+                   [0.5, 1.0, -1.5]]);
+    let model = CustomMatrix::new(q, &[0.2, 0.4, 0.4], b"XYZ", 1.0).unwrap();
+    assert_eq!(model.n_states(), 3);
+
+    let mut rng = StdRng::seed_from_u64(5);
+    let root = Sequence::from_str(&"X".repeat(30_000),
+        model.frequencies()).unwrap();
+    assert_eq!(model.mutate(&root, 0.0, &mut rng).unwrap().as_str(),
+        root.as_str());
+
+    // A long branch reaches the equilibrium frequencies
+    let tip = model.mutate(&root, 20.0, &mut rng).unwrap();
+    for (&symbol, &freq) in b"XYZ".iter().zip(&[0.2, 0.4, 0.4]) {
+        let count = tip.nucleotides.iter().filter(|&&b| b == symbol).count();
+        let observed = count as f64 / 30_000.0;
+        assert!((observed - freq).abs() < 0.02, "{} at {}", symbol as char,
+            observed);
+    }
+
+    let bad = Sequence::from_vec(b"XYA".to_vec(), model.frequencies());
+    assert_eq!(model.mutate(&bad, 0.1, &mut rng).err(),
+        Some(MutationError::UnknownSymbol { symbol: b'A', position: 2 }));
+}
+
+#[test]
+fn hky_rows_are_distributions_across_parameters() {
+    let freqs = [[0.25; 4], [0.1, 0.2, 0.3, 0.4], [0.01, 0.49, 0.3, 0.2]];
+    for f in freqs.iter() {
+        for &kappa in [0.0, 0.1, 1.0, 4.0, 50.0].iter() {
+            let model = hky_with(*f, kappa);
+            for &v in BRANCH_LENGTHS.iter().chain(&[1e-9, 100.0]) {
+                assert_stochastic(&model.transition_matrix(v));
+            }
+        }
+    }
+}
+
+#[test]
+fn hky_without_purines_is_an_error() {
+    let err = HKY::new(0.0, 0.0, 0.5, 0.5, b'A', b'G', b'C', b'T', 2.0, 1.0)
+        .err().unwrap();
+    assert!(err.contains("Base frequencies must be positive"), "{}", err);
+    assert!(HKY::new(0.5, 0.5, 0.0, 0.0, b'A', b'G', b'C', b'T', 2.0, 1.0)
+        .is_err());
+}
+
+#[test]
+fn jc69_rate_matrix_file_is_jc69() {
+    let model = parsers::parse_rate_matrix(data("jc69.q"), *b"AGCT", 1.0)
+        .unwrap();
+    assert_eq!(model.equilibrium_frequencies(), [0.25; 4]);
+    for &v in BRANCH_LENGTHS.iter() {
+        let (custom, jc) = (model.transition_matrix(v),
+            jc69().transition_matrix(v));
+        assert!(custom.iter().zip(jc.iter()).all(|(x, y)| (x - y).abs() < 1e-9),
+            "{} != {}", custom, jc);
+    }
+}
+
+// Check 'model' only through the Mutator trait
+fn assert_alphabet(model: &dyn Mutator, alphabet: &[u8], freqs: &[f64]) {
+    assert_eq!(model.alphabet(), alphabet);
+    assert_eq!(model.equilibrium_frequencies().len(), alphabet.len());
+    for (f, expected) in model.equilibrium_frequencies().iter().zip(freqs) {
+        assert!((f - expected).abs() < 1e-12, "{} for {}", f, expected);
+    }
+}
+
+#[test]
+fn models_are_queried_through_the_trait() {
+    let freqs = [0.1, 0.2, 0.3, 0.4];
+    assert_alphabet(&hky_with(freqs, 2.0), b"AGCT", &freqs);
+    assert_alphabet(&jc69(), b"AGCT", &[0.25; 4]);
+    let rna = F81::new([0.4, 0.1, 0.1, 0.4], *b"AGCU", 1.0).unwrap();
+    assert_alphabet(&rna, b"AGCU", &[0.4, 0.1, 0.1, 0.4]);
+
+    let protein: Box<dyn Mutator> = Box::new(PoissonAA::new(1.0));
+    assert_eq!(protein.alphabet().len(), 20);
+    assert_alphabet(protein.as_ref(), protein.alphabet(), &[0.05; 20]);
+}
+
+#[test]
+fn full_matrix_caches_keep_building() {
+    let cache = MatrixCache::new();
+    let model = jc69();
+    for _ in 0..3 {
+        cache.get_or_build(0.5, |v| model.transition_matrix(v));
+    }
+    assert_eq!(cache.builds(), 1);
+
+    // Past its capacity the cache stops growing, but still hands out
+    // matrices for new lengths
+    for i in 0..5000 {
+        cache.get_or_build(1.0 + i as f64 * 1e-3,
+            |v| model.transition_matrix(v));
+    }
+    assert_eq!(cache.builds(), 5001);
+    let m = cache.get_or_build(100.0, |v| model.transition_matrix(v));
+    assert_eq!(cache.builds(), 5002);
+    assert_stochastic(&m);
+    cache.get_or_build(100.0, |v| model.transition_matrix(v));
+    assert_eq!(cache.builds(), 5003);
+}
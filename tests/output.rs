@@ -0,0 +1,92 @@
+use aminosim::output;
+
+fn fasta(seq: &str, width: usize) -> Vec<String> {
+    let mut out = Vec::<u8>::new();
+    output::write_fasta_record(&mut out, "A", seq, width).unwrap();
+    String::from_utf8(out).unwrap().lines().map(String::from).collect()
+}
+
+#[test]
+fn fasta_wraps_at_the_given_width() {
+    let seq = "ACGT".repeat(40);
+
+    let lines = fasta(&seq, 60);
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], ">A");
+    assert_eq!(lines[1].len(), 60);
+    assert_eq!(lines[3].len(), 40);
+    assert_eq!(lines[1..].concat(), seq);
+
+    assert_eq!(fasta(&seq, 80).len(), 3);
+    assert_eq!(fasta(&seq, 0), [">A", seq.as_str()]);
+}
+
+fn alignment(seqs: &[(&str, &str)]) -> Vec<(String, String)> {
+    seqs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn invariant_sites_are_removed() {
+    let mut seqs = alignment(&[("A", "AACGT"), ("B", "AACTT"), ("C", "AGCGT")]);
+    assert_eq!(aminosim::remove_invariant_sites(&mut seqs, 1), 3);
+    assert_eq!(seqs, alignment(&[("A", "AG"), ("B", "AT"), ("C", "GG")]));
+
+    // Codons are kept or removed whole
+    let mut seqs = alignment(&[("A", "AAACCCGGG"), ("B", "AAACCTGGG")]);
+    assert_eq!(aminosim::remove_invariant_sites(&mut seqs, 3), 2);
+    assert_eq!(seqs, alignment(&[("A", "CCC"), ("B", "CCT")]));
+}
+
+#[test]
+fn output_files_are_replaced_unless_appended() {
+    use std::io::Write;
+
+    let path = format!("{}/replaced.txt", env!("CARGO_TARGET_TMPDIR"));
+    let write = |out: std::io::Result<output::OutputFile>, text: &str| {
+        let mut out = out.unwrap();
+        out.write_all(text.as_bytes()).unwrap();
+        out.finish().unwrap();
+    };
+
+    write(output::OutputFile::create(&path), "a much longer first result\n");
+    write(output::OutputFile::create(&path), "short\n");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "short\n");
+
+    write(output::OutputFile::append(&path), "more\n");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "short\nmore\n");
+}
+
+// Value of 'key' in a NEXUS "DIMENSIONS key=value;" command
+fn nexus_dimension(nexus: &str, key: &str) -> usize {
+    let start = nexus.find(&format!("{}=", key)).unwrap() + key.len() + 1;
+    let end = start + nexus[start..].find(';').unwrap();
+    nexus[start..end].parse().unwrap()
+}
+
+#[test]
+fn nexus_header_matches_its_matrix() {
+    let seqs = alignment(&[("A", "ACGTAC"), ("B b", "ACGTTC"),
+        ("C's", "AGGTAC")]);
+    let mut out = Vec::new();
+    output::write_nexus(&mut out, &seqs, output::DataType::DNA).unwrap();
+    let nexus = String::from_utf8(out).unwrap();
+
+    assert!(nexus.starts_with("#NEXUS\n"));
+    assert!(nexus.contains("DATATYPE=DNA"));
+    let ntax = nexus_dimension(&nexus, "NTAX");
+    let nchar = nexus_dimension(&nexus, "NCHAR");
+
+    let matrix: Vec<&str> = nexus.lines()
+        .skip_while(|l| l.trim() != "MATRIX").skip(1)
+        .take_while(|l| l.trim() != ";")
+        .collect();
+    assert_eq!(matrix.len(), ntax);
+    assert!(matrix.iter().all(|row| row.rsplit(' ').next().unwrap().len()
+        == nchar));
+    assert!(matrix[1].trim_start().starts_with("'B b'"));
+    assert!(matrix[2].trim_start().starts_with("'C''s'"));
+
+    let ragged = alignment(&[("A", "ACGT"), ("B", "ACG")]);
+    assert!(output::write_nexus(&mut Vec::new(), &ragged,
+        output::DataType::DNA).is_err());
+}
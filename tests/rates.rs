@@ -0,0 +1,77 @@
+mod common;
+
+use aminosim::rates::RateHeterogeneity;
+use aminosim::SimulationOptions;
+use common::{jc69, tree};
+
+// Sites in each block of rates
+const BLOCK: usize = 5000;
+
+// Sites of 'a' and 'b' in 'block' that differ
+fn differences(a: &str, b: &str, block: usize) -> usize {
+    a.bytes().zip(b.bytes()).skip(block * BLOCK).take(BLOCK)
+        .filter(|(x, y)| x != y).count()
+}
+
+#[test]
+fn fixed_rates_scale_substitutions() {
+    let t = tree("(A:0.05,B:0.05)R;", 3 * BLOCK);
+
+    // Rates 0, 1 and 2 for consecutive blocks of sites
+    let rates = (0..3).flat_map(|r| vec![r as f64; BLOCK]).collect();
+    let mut opts = SimulationOptions::new(11);
+    opts.ancestral = true;
+    opts.tree_site_rates = Some(vec![RateHeterogeneity::fixed(rates)
+        .unwrap()]);
+
+    let model = jc69();
+    let seqs = aminosim::simulate(&mut [t], &model, &opts).unwrap();
+
+    assert_eq!(differences(&seqs["A"], &seqs["R"], 0), 0);
+    let once = differences(&seqs["A"], &seqs["R"], 1) as f64;
+    let twice = differences(&seqs["A"], &seqs["R"], 2) as f64;
+    let ratio = twice / once;
+    assert!(ratio > 1.6 && ratio < 2.4, "{} vs {}", once, twice);
+}
+
+#[test]
+fn faster_gamma_categories_change_more() {
+    let sites = 40_000;
+    let t = tree("(A:0.2,B:0.2)R;", sites);
+
+    let mut opts = SimulationOptions::new(6);
+    opts.ancestral = true;
+    opts.site_rates = RateHeterogeneity::gamma(0.5, Some(4)).unwrap();
+
+    let model = jc69();
+    let mut trees = [t];
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    let rates = trees[0].site_rates().unwrap();
+    assert_eq!(rates.len(), sites);
+
+    // Share of sites differing between the root and a tip, per category
+    let mut categories: Vec<f64> = rates.to_vec();
+    categories.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    categories.dedup();
+    assert_eq!(categories.len(), 4);
+    let shares: Vec<f64> = categories.iter().map(|&rate| {
+        let (mut changed, mut total) = (0, 0);
+        for ((r, a), root) in rates.iter().zip(seqs["A"].bytes())
+            .zip(seqs["R"].bytes()) {
+            if *r == rate {
+                total += 1;
+                changed += (a != root) as usize;
+            }
+        }
+        changed as f64 / total as f64
+    }).collect();
+
+    for pair in shares.windows(2) {
+        assert!(pair[0] < pair[1], "{:?}", shares);
+    }
+    for (&rate, &share) in categories.iter().zip(&shares) {
+        let expected = 0.75 * (1.0 - (-4.0 / 3.0 * 0.2 * rate).exp());
+        assert!((share - expected).abs() < 0.02, "{} at rate {}", share,
+            rate);
+    }
+}
@@ -0,0 +1,112 @@
+use aminosim::sequence::Sequence;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn nucleotides() -> Vec<(u8, f64)> {
+    vec![(b'A', 0.1), (b'G', 0.2), (b'C', 0.3), (b'T', 0.4)]
+}
+
+#[test]
+fn sequences_are_built_from_strings() {
+    let s = Sequence::from_str("ACGTACGT", &nucleotides()).unwrap();
+    assert_eq!(s.as_str(), "ACGTACGT");
+    assert_eq!(s.len(), 8);
+}
+
+#[test]
+fn symbols_outside_the_alphabet_are_rejected() {
+    let err = match Sequence::from_str("ACGUACGT", &nucleotides()) {
+        Ok(_) => panic!("U isn't a symbol of the table"),
+        Err(e) => e
+    };
+    assert!(err.contains("'U' at position 4"), "{}", err);
+
+    // Ambiguity codes aren't symbols of the table either
+    assert!(Sequence::from_str("ACNT", &nucleotides()).is_err());
+}
+
+#[test]
+fn seeded_sampling_is_deterministic() {
+    let draw = |seed| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut s = Sequence::new(&nucleotides(), 500, &mut rng);
+        s.append(500, &mut rng);
+        s
+    };
+    assert_eq!(draw(3).as_str(), draw(3).as_str());
+    assert_ne!(draw(3).as_str(), draw(4).as_str());
+
+    // Reusing an RNG across calls draws the same symbols as a single call
+    let mut rng = StdRng::seed_from_u64(3);
+    assert_eq!(Sequence::new(&nucleotides(), 1000, &mut rng).as_str(),
+        draw(3).as_str());
+}
+
+#[test]
+fn non_ascii_symbols_never_make_a_str() {
+    assert!(Sequence::from_ascii("ACÉT".as_bytes(), b"AGCT").is_err());
+
+    // Even if one gets in, it's only ever reported
+    let mut s = Sequence::from_str("ACGT", &nucleotides()).unwrap();
+    s.nucleotides.push(0xC3);
+    assert!(s.try_to_string().is_err());
+    assert!(std::panic::catch_unwind(|| s.as_str().len()).is_err());
+}
+
+#[test]
+fn ambiguity_codes_resolve_to_the_frequencies() {
+    let sites = 40_000;
+    let mut s = Sequence::from_ascii(&vec![b'N'; sites], b"AGCT").unwrap();
+    s.resolve_ambiguities(&nucleotides(), &mut StdRng::seed_from_u64(1));
+
+    for &(base, freq) in nucleotides().iter() {
+        let count = s.nucleotides.iter().filter(|&&b| b == base).count();
+        let observed = count as f64 / sites as f64;
+        assert!((observed - freq).abs() < 0.01, "{} at {}", base as char,
+            observed);
+    }
+
+    // Partial codes only resolve to their own bases, R being A or G
+    let mut s = Sequence::from_ascii(&vec![b'R'; 1000], b"AGCT").unwrap();
+    s.resolve_ambiguities(&nucleotides(), &mut StdRng::seed_from_u64(1));
+    assert!(s.nucleotides.iter().all(|b| b"AG".contains(b)));
+}
+
+#[test]
+fn length_counts_appended_symbols() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut s = Sequence::new(&nucleotides(), 0, &mut rng);
+    assert!(s.is_empty());
+
+    s.append(25, &mut rng);
+    assert_eq!(s.len(), 25);
+    s.append(75, &mut rng);
+    assert_eq!(s.len(), 100);
+    assert_eq!(s.as_str().len(), s.len());
+}
+
+#[test]
+fn slices_concatenate_back_to_the_sequence() {
+    let s = Sequence::from_str("ACGTTGCAAC", &nucleotides()).unwrap();
+    let (head, tail) = (s.slice(0, 4), s.slice(4, 10));
+    assert_eq!(head.as_str(), "ACGT");
+    assert_eq!(s.slice(3, 3).len(), 0);
+
+    let joined = head.concat(&tail);
+    assert_eq!(joined.len(), head.len() + tail.len());
+    assert_eq!(joined.as_str(), s.as_str());
+    assert_eq!(joined.concat(&s).len(), 20);
+}
+
+#[test]
+#[should_panic(expected = "out of bounds")]
+fn slices_past_the_end_panic() {
+    Sequence::from_str("ACGT", &nucleotides()).unwrap().slice(2, 5);
+}
+
+#[test]
+#[should_panic(expected = "out of bounds")]
+fn reversed_slices_panic() {
+    Sequence::from_str("ACGT", &nucleotides()).unwrap().slice(3, 1);
+}
@@ -0,0 +1,374 @@
+mod common;
+
+use aminosim::mutator::{HKY, Mutator};
+use aminosim::parsers;
+use aminosim::sequence::Sequence;
+use aminosim::tree::{self, NegativeBranches, NTree};
+use aminosim::SimulationOptions;
+use common::{data, hky, tree};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use std::collections::BTreeMap;
+
+fn partitioned_trees() -> Vec<NTree> {
+    parsers::parse_newick_partitioned(data("two_trees.nwk"),
+        data("two_trees.partitions"), None, None, None,
+        NegativeBranches::Error)
+        .unwrap().0
+}
+
+fn simulate(trees: &mut [NTree], model: &dyn Mutator, seed: u64) ->
+    BTreeMap<String, String> {
+    aminosim::simulate(trees, model, &SimulationOptions::new(seed)).unwrap()
+}
+
+#[test]
+fn partitioned_sequences_span_every_partition() {
+    let mut trees = partitioned_trees();
+    assert_eq!(trees.len(), 2);
+
+    let seqs = simulate(&mut trees, &hky(), 1);
+    assert_eq!(seqs.keys().collect::<Vec<_>>(), ["A", "B", "C"]);
+    assert!(seqs.values().all(|s| s.len() == 12));
+}
+
+#[test]
+fn fixed_seed_output_is_exact() {
+    let mut trees = partitioned_trees();
+    let seqs = simulate(&mut trees, &hky(), 42);
+
+    let expected: BTreeMap<String, String> = [
+        ("A", "CGAAACGTAAAG"),
+        ("B", "GCAAACGTAAAG"),
+        ("C", "CGAAACGCAAAG")
+    ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    assert_eq!(seqs, expected);
+}
+
+#[test]
+fn trees_can_be_simulated_again() {
+    let mut trees = partitioned_trees();
+    let first = simulate(&mut trees, &hky(), 7);
+    let second = simulate(&mut trees, &hky(), 7);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn malformed_tree_is_an_error() {
+    let err = parsers::parse_newick_single(data("malformed.nwk"), 10, None,
+        NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 1:"), "{}", msg);
+    assert!(msg.contains("Unbalanced parens"), "{}", msg);
+}
+
+#[test]
+fn trifurcating_root_evolves_every_tip() {
+    let mut trees = parsers::parse_newick_single(data("trifurcating.nwk"),
+        20, None, NegativeBranches::Error).unwrap().0;
+    assert_eq!(trees[0].n_tips(), 5);
+
+    let seqs = simulate(&mut trees, &hky(), 3);
+    assert_eq!(seqs.keys().collect::<Vec<_>>(), ["A", "B", "C", "D", "E"]);
+    assert!(seqs.values().all(|s| s.len() == 20));
+}
+
+#[test]
+fn partitions_over_max_bases_are_an_error() {
+    let err = parsers::parse_newick_partitioned(data("two_trees.nwk"),
+        data("huge.partitions"), None, Some(1_000_000), None,
+        NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 2:"), "{}", msg);
+    assert!(msg.contains("maximum of 1000000 bases"), "{}", msg);
+}
+
+#[test]
+fn burnin_brings_roots_to_equilibrium() {
+    let sites = 20_000;
+    let freqs = [(b'A', 0.1), (b'G', 0.2), (b'C', 0.3), (b'T', 0.4)];
+    let model = HKY::new(0.1, 0.2, 0.3, 0.4, b'A', b'G', b'C', b'T', 2.0,
+        1.0).unwrap();
+    let root = Sequence::from_ascii(&vec![b'A'; sites], model.alphabet())
+        .unwrap();
+
+    let t = tree("(A:0.1,B:0.1)R;", sites);
+    let mut opts = SimulationOptions::new(4);
+    opts.ancestral = true;
+    opts.root_sequences = Some(vec![root]);
+    opts.burnin = 10.0;
+
+    let seqs = aminosim::simulate(&mut [t], &model, &opts).unwrap();
+    for (base, freq) in freqs.iter() {
+        let count = seqs["R"].bytes().filter(|b| b == base).count();
+        let observed = count as f64 / sites as f64;
+        assert!((observed - freq).abs() < 0.02, "{} at {}", *base as char,
+            observed);
+    }
+}
+
+#[test]
+fn roots_are_drawn_from_root_frequencies() {
+    let sites = 20_000;
+    let composition = |s: &str, base: u8| {
+        s.bytes().filter(|&b| b == base).count() as f64 / sites as f64
+    };
+
+    let t = tree("(A:10,B:0.01)R;", sites);
+    let mut opts = SimulationOptions::new(6);
+    opts.ancestral = true;
+    opts.root_frequencies = Some(vec![0.7, 0.1, 0.1, 0.1]);
+
+    // The root follows the given composition, a deep tip the model's
+    let seqs = aminosim::simulate(&mut [t], &hky(), &opts).unwrap();
+    assert!((composition(&seqs["R"], b'A') - 0.7).abs() < 0.02);
+    assert!((composition(&seqs["B"], b'A') - 0.7).abs() < 0.02);
+    for &base in b"AGCT" {
+        let observed = composition(&seqs["A"], base);
+        assert!((observed - 0.25).abs() < 0.02, "{} at {}", base as char,
+            observed);
+    }
+}
+
+#[test]
+fn tree_subsets_keep_their_partitions_and_seeds() {
+    let (mut subset, n_trees) = parsers::parse_newick_partitioned(
+        data("two_trees.nwk"), data("two_trees.partitions"), None, None,
+        Some(2..=2), NegativeBranches::Error).unwrap();
+    assert_eq!((subset.len(), n_trees), (1, 2));
+    assert_eq!(subset[0].get_partition(), 7);
+
+    // The second tree evolves as it does in a run over both
+    let full = simulate(&mut partitioned_trees(), &hky(), 3);
+    let mut opts = SimulationOptions::new(3);
+    opts.first_tree = 1;
+    let seqs = aminosim::simulate(&mut subset, &hky(), &opts).unwrap();
+    for (taxon, seq) in seqs.iter() {
+        assert_eq!(seq, &full[taxon][5..]);
+    }
+
+    // Replicate 2 starts one block of seeds later, a block per input tree
+    opts.seed = 3 + n_trees as u64;
+    let full = simulate(&mut partitioned_trees(), &hky(), opts.seed);
+    let seqs = aminosim::simulate(&mut subset, &hky(), &opts).unwrap();
+    for (taxon, seq) in seqs.iter() {
+        assert_eq!(seq, &full[taxon][5..]);
+    }
+
+    let err = parsers::parse_newick_single(data("two_trees.nwk"), 10,
+        Some(2..=3), NegativeBranches::Error).err().unwrap();
+    assert!(err.to_string().contains("only 2 trees"), "{}", err);
+}
+
+#[test]
+fn parse_errors_give_the_line() {
+    let err = parsers::parse_newick_single(data("missing_semicolon.nwk"), 10,
+        None, NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 3:"), "{}", msg);
+    assert!(msg.contains("missing trailing ';'"), "{}", msg);
+    assert!(msg.contains("((A:0.2,B:0.2):0.2,C:0.2)"), "{}", msg);
+
+    let err = parsers::parse_newick_partitioned(data("two_trees.nwk"),
+        data("bad.partitions"), None, None, None, NegativeBranches::Error)
+        .err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 2:"), "{}", msg);
+    assert!(msg.contains("'7 bases'"), "{}", msg);
+}
+
+#[test]
+fn first_malformed_tree_in_the_file_is_reported() {
+    let err = parsers::parse_newick_single(data("two_bad_trees.nwk"), 10,
+        None, NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 2:"), "{}", msg);
+    assert!(msg.contains("extra ')'"), "{}", msg);
+}
+
+// Copy of the test data file 'name', gzipped, in a temporary directory
+fn gzipped(name: &str) -> String {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let path = format!("{}/{}.gz", env!("CARGO_TARGET_TMPDIR"), name);
+    let mut gz = GzEncoder::new(std::fs::File::create(&path).unwrap(),
+        Compression::default());
+    gz.write_all(&std::fs::read(data(name)).unwrap()).unwrap();
+    gz.finish().unwrap();
+    path
+}
+
+#[test]
+fn gzipped_inputs_are_read() {
+    let trees = parsers::parse_newick_single(gzipped("trifurcating.nwk"), 20,
+        None, NegativeBranches::Error).unwrap().0;
+    assert_eq!(trees.len(), 1);
+    assert_eq!(trees[0].n_tips(), 5);
+
+    let mut trees = parsers::parse_newick_partitioned(
+        gzipped("two_trees.nwk"), gzipped("two_trees.partitions"), None, None,
+        None, NegativeBranches::Error).unwrap().0;
+    assert_eq!(simulate(&mut trees, &hky(), 4),
+        simulate(&mut partitioned_trees(), &hky(), 4));
+}
+
+#[test]
+fn unknown_symbols_are_errors_not_panics() {
+    let model = hky();
+    let mut rng = StdRng::seed_from_u64(1);
+    let s = Sequence::from_vec(b"ACGNT".to_vec(), model.frequencies());
+    let err = model.mutate(&s, 0.1, &mut rng).err().unwrap();
+    assert!(err.to_string().contains("'N' at position 4"), "{}", err);
+
+    // Ambiguity codes in a root are resolved, anything else stops the run
+    let t = tree("(A:0.1,B:0.2);", 5);
+    let mut opts = SimulationOptions::new(1);
+    opts.root_sequences = Some(vec![Sequence::from_vec(b"AC?GT".to_vec(),
+        model.frequencies())]);
+    let err = aminosim::simulate(&mut [t], &model, &opts).err().unwrap();
+    assert!(err.to_string().contains("'?' at position 3"), "{}", err);
+}
+
+#[test]
+fn taxa_missing_from_a_partition_are_padded_with_gaps() {
+    let build = || vec![tree("((A:0.1,B:0.2):0.1,C:0.3);", 30),
+        tree("(A:0.1,B:0.2);", 20)];
+
+    // Without filling, C is left shorter than the rest
+    let mut opts = SimulationOptions::new(3);
+    let seqs = aminosim::simulate(&mut build(), &hky(), &opts).unwrap();
+    assert_eq!(seqs["A"].len(), 50);
+    assert_eq!(seqs["C"].len(), 30);
+
+    opts.fill_missing = true;
+    let filled = aminosim::simulate(&mut build(), &hky(), &opts).unwrap();
+    assert!(filled.values().all(|s| s.len() == 50));
+    assert_eq!(filled["C"][..30], seqs["C"]);
+    assert_eq!(filled["C"][30..], "-".repeat(20));
+    assert!(!filled["A"].contains('-'));
+}
+
+#[test]
+fn partitions_are_assembled_in_input_order() {
+    let newicks = ["((A:0.1,B:0.2):0.1,C:0.3);", "((A:0.4,C:0.2):0.1,B:0.3);",
+        "(A:0.2,(B:0.1,C:0.5):0.2);"];
+    let lengths = [7, 13, 4];
+    let build = |i: usize| tree(newicks[i], lengths[i]);
+
+    let mut trees: Vec<NTree> = (0..3).map(build).collect();
+    let opts = SimulationOptions::new(9);
+    let seqs = aminosim::simulate(&mut trees, &hky(), &opts).unwrap();
+
+    // Tree i is seeded with seed + i, so each partition can be simulated on
+    // its own
+    let mut expected = BTreeMap::<String, String>::new();
+    for i in 0..3 {
+        let part = aminosim::simulate(&mut [build(i)], &hky(),
+            &SimulationOptions::new(9 + i as u64)).unwrap();
+        for (id, s) in part {
+            expected.entry(id).or_default().push_str(&s);
+        }
+    }
+
+    assert_eq!(seqs, expected);
+    assert!(seqs.values().all(|s| s.len() == 24));
+}
+
+#[test]
+fn nexus_translate_tables_restore_taxon_names() {
+    let trees = parsers::parse_newick_single(data("translated.nex"), 10, None,
+        NegativeBranches::Error).unwrap().0;
+    assert_eq!(trees.len(), 2);
+    assert_eq!(trees[0].output_ids(false),
+        ["human", "chimp", "gorilla gorilla"]);
+    assert_eq!(trees[1].output_ids(false),
+        ["human", "gorilla gorilla", "chimp"]);
+}
+
+#[test]
+fn partition_comments_are_skipped() {
+    let trees = parsers::parse_newick_partitioned(data("two_trees.nwk"),
+        data("commented.partitions"), Some(12), None, None,
+        NegativeBranches::Error).unwrap().0;
+    let lengths: Vec<usize> = trees.iter().map(|t| t.get_partition())
+        .collect();
+    assert_eq!(lengths, [5, 7]);
+    assert_eq!(trees[1].output_ids(false), ["A", "B", "C"]);
+}
+
+#[test]
+fn shared_roots_are_slices_of_one_sequence() {
+    let mut trees: Vec<NTree> = [(8, "(A:0.1,B:0.2)R;"), (5, "(B:0.3,A:0.1)R;"),
+        (11, "((A:0.2,B:0.1):0.1)R;")].iter()
+        .map(|&(sites, newick)| tree(newick, sites))
+        .collect();
+
+    let mut opts = SimulationOptions::new(21);
+    opts.shared_root = true;
+    opts.ancestral = true;
+    let seqs = aminosim::simulate(&mut trees, &hky(), &opts).unwrap();
+
+    // The root is drawn once, with the complement of the seed
+    let root = tree::random_root(&hky(), 24, None,
+        &mut StdRng::seed_from_u64(!21));
+    assert_eq!(seqs["R"], root.as_str());
+}
+
+#[test]
+fn repeated_branch_lengths_share_a_matrix() {
+    let model = hky();
+    let t = tree("((A:0.1,B:0.2):0.1,((C:0.2,D:0.1):0.3,E:0.3):0.1)R;",
+        50);
+    aminosim::simulate(&mut [t], &model, &SimulationOptions::new(1))
+        .unwrap();
+    assert_eq!(model.matrix_builds(), 3);
+}
+
+// File 'name' in the test scratch directory, holding 'contents'
+fn scratch(name: &str, contents: &str) -> String {
+    let path = format!("{}/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn trees_and_partitions_must_pair_up() {
+    let trees = std::fs::read_to_string(data("two_trees.nwk")).unwrap();
+    let extra_tree = scratch("three_trees.nwk", &(trees + "(A:1,B:1);\n"));
+    let err = parsers::parse_newick_partitioned(extra_tree,
+        data("two_trees.partitions"), None, None, None,
+        NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 3: Tree has no partition"), "{}", msg);
+
+    let extra_part = scratch("three.partitions", "5\n7\n# a comment\n9\n");
+    let err = parsers::parse_newick_partitioned(data("two_trees.nwk"),
+        extra_part, None, None, None, NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.starts_with("Line 4: Partition has no tree"), "{}", msg);
+}
+
+#[test]
+fn empty_tree_files_are_errors() {
+    let empty = scratch("no_trees.nwk", "\n\n");
+    let err = parsers::parse_newick_single(&empty, 10, None,
+        NegativeBranches::Error).err().unwrap();
+    assert!(err.to_string().contains("No trees were found"), "{}", err);
+
+    let err = parsers::parse_newick_partitioned(empty,
+        scratch("no_trees.partitions", ""), None, None, None,
+        NegativeBranches::Error).err().unwrap();
+    assert!(err.to_string().contains("No trees were found"), "{}", err);
+}
+
+#[test]
+fn translated_tips_must_stay_unique() {
+    let err = parsers::parse_newick_single(data("repeated_translate.nex"), 10,
+        None, NegativeBranches::Error).err().unwrap();
+    let msg = err.to_string();
+    assert!(msg.contains("Tip label 'human' appears more than once after \
+        translating"), "{}", msg);
+}
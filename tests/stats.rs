@@ -0,0 +1,93 @@
+mod common;
+
+use aminosim::mutator::{HKY, Mutator};
+use aminosim::stats;
+use aminosim::SimulationOptions;
+use common::{jc69, tree};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn simulate_jc69(newick: &str, sites: usize, opts: &SimulationOptions) ->
+    Vec<(String, String)> {
+    let t = tree(newick, sites);
+    aminosim::simulate(&mut [t], &jc69(), opts).unwrap().into_iter()
+        .collect()
+}
+
+// Expected fraction of sites differing after a JC69 branch of length 'v'
+fn jc69_p_distance(v: f64) -> f64 {
+    0.75 * (1.0 - (-4.0 / 3.0 * v).exp())
+}
+
+#[test]
+fn jc69_composition_is_even() {
+    let seqs = simulate_jc69("(A:0.5,B:1,C:2);", 40_000,
+        &SimulationOptions::new(11));
+    let (per_taxon, overall) = stats::composition(&seqs);
+    assert_eq!(per_taxon.len(), 3);
+    assert_eq!(overall.total(), 120_000);
+
+    for &b in b"AGCT" {
+        let f = overall.fraction(b);
+        assert!((f - 0.25).abs() < 0.01, "{} at {}", b as char, f);
+    }
+    assert!((overall.gc_content().unwrap() - 0.5).abs() < 0.01);
+
+    let mut report = Vec::new();
+    stats::write_report(&mut report, &seqs, 3).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert_eq!(report.lines().count(), 5);
+    assert!(report.starts_with("taxon\tlength\tA\tC\tG\tT\tGC\n"), "{}",
+        report);
+}
+
+#[test]
+fn divergence_follows_branch_length() {
+    let sites = 40_000;
+    let mut rng = StdRng::seed_from_u64(2);
+    let root = jc69().random(sites, &mut rng);
+    let tip = jc69().mutate(&root, 0.2, &mut rng).unwrap();
+    let p = root.hamming_distance(&tip) as f64 / sites as f64;
+    assert!((p - jc69_p_distance(0.2)).abs() < 0.01, "{}", p);
+
+    // Same through the report, against the root of a simulation
+    let mut opts = SimulationOptions::new(4);
+    opts.ancestral = true;
+    let seqs = simulate_jc69("(A:0.05,B:0.5)R;", sites, &opts);
+    let mut report = Vec::new();
+    stats::write_divergence(&mut report, &seqs, "R", 4).unwrap();
+    let report = String::from_utf8(report).unwrap();
+
+    let rows: Vec<Vec<&str>> = report.lines().skip(1)
+        .map(|l| l.split('\t').collect())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    for (row, v) in rows.iter().zip(&[0.05, 0.5]) {
+        let p: f64 = row[3].parse().unwrap();
+        assert!((p - jc69_p_distance(*v)).abs() < 0.01, "{}", report);
+    }
+}
+
+#[test]
+fn unnormalized_hky_runs_on_rate_matrix_time() {
+    let sites = 40_000;
+    let (v, seed) = (0.4, 6);
+    let p_distance = |m: &HKY| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let root = m.random(sites, &mut rng);
+        let tip = m.mutate(&root, v, &mut rng).unwrap();
+        root.hamming_distance(&tip) as f64 / sites as f64
+    };
+
+    // With even frequencies and kappa 1 HKY is JC69, whose unnormalized
+    // matrix makes 0.75 substitutions per site per unit of time
+    let normalized = HKY::new(0.25, 0.25, 0.25, 0.25, b'A', b'G', b'C', b'T',
+        1.0, 1.0).unwrap();
+    let unnormalized = HKY::new_unnormalized(0.25, 0.25, 0.25, 0.25, b'A',
+        b'G', b'C', b'T', 1.0, 1.0).unwrap();
+    let (p, raw) = (p_distance(&normalized), p_distance(&unnormalized));
+    assert!((p - jc69_p_distance(v)).abs() < 0.01, "{}", p);
+    assert!((raw - jc69_p_distance(0.75 * v)).abs() < 0.01, "{}", raw);
+    assert!(raw < p);
+}
@@ -0,0 +1,326 @@
+mod common;
+
+use aminosim::mutator::JC69;
+use aminosim::tree::{NegativeBranches, NTree};
+use aminosim::SimulationOptions;
+use common::{jc69, tree};
+
+#[test]
+fn zero_length_tip_branches_are_counted() {
+    let t = tree("((A:0,B:0.5):0,C:1e-9);", 1);
+    assert_eq!(t.short_tip_branches(1e-8), 2);
+    assert_eq!(t.short_tip_branches(0.0), 0);
+    assert_eq!(tree("((A:0.1,B:0.5):0,C:0.2);", 1).short_tip_branches(1e-8),
+        0);
+}
+
+#[test]
+fn zero_length_tip_copies_its_parent() {
+    let mut trees = vec![tree("((A:0,B:0.5)X:0.1,C:0.2);", 200)];
+    let mut opts = SimulationOptions::new(5);
+    opts.ancestral = true;
+
+    let model = jc69();
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    assert_eq!(seqs["A"], seqs["X"]);
+    assert_ne!(seqs["B"], seqs["X"]);
+}
+
+#[test]
+fn internal_labels_are_ancestral_ids() {
+    let mut trees = vec![tree("((A:0.1,B:0.2)AncAB:1,(C:0.1,D:0.1):0.5)Root;",
+        30)];
+    let mut opts = SimulationOptions::new(1);
+    opts.ancestral = true;
+
+    let model = jc69();
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    assert_eq!(seqs.keys().collect::<Vec<_>>(),
+        ["A", "AncAB", "B", "C", "D", "Root", "node_2"]);
+}
+
+#[test]
+fn substitutions_grow_with_branch_length() {
+    let sites = 50_000;
+    let mut trees = vec![tree("(A:0.01,B:0.05,C:0.1)R;", sites)];
+    let mut opts = SimulationOptions::new(9);
+    opts.count_substitutions = true;
+
+    let model = jc69();
+    aminosim::simulate(&mut trees, &model, &opts).unwrap();
+
+    let branches = trees[0].branch_substitutions();
+    let ids: Vec<_> = branches.iter().map(|b| b.0.unwrap()).collect();
+    assert_eq!(ids, ["A", "B", "C"]);
+    assert!(branches.windows(2).all(|w| w[0].2 < w[1].2));
+
+    // Repeated hits on a site show as a single change, so counts fall a
+    // little short of v * sites
+    for (_, v, substitutions) in branches {
+        let expected = v * sites as f64;
+        let observed = substitutions as f64;
+        assert!((observed - expected).abs() < 0.15 * expected,
+            "{} substitutions on a branch of length {}", observed, v);
+    }
+}
+
+#[test]
+fn ultrametric_trees_are_recognized() {
+    let t = tree("((A:0.3,B:0.3):0.2,(C:0.1,D:0.1):0.4);", 1);
+    assert!(t.is_ultrametric(1e-9));
+
+    let t = tree("((A:0.3,B:0.5):0.2,C:0.5);", 1);
+    assert!(!t.is_ultrametric(1e-9));
+    assert!(t.is_ultrametric(0.2));
+}
+
+#[test]
+fn whitespace_separates_tokens() {
+    let t = tree("( A : 0.1 ,\n\t'B C':0.2 [&&NHX:S=x] ,\r\n (D:0.3,E:0.4)\n\
+        X : 0.5 )\n;", 1);
+    assert_eq!(t.n_tips(), 4);
+    assert_eq!(t.output_ids(true), ["A", "B C", "X", "D", "E"]);
+    assert!((t.total_length() - 1.5).abs() < 1e-12);
+
+    for newick in ["(A B:0.1,C:0.2);", "(A:0. 1,C:0.2);"].iter() {
+        let mut t = NTree::new(1, newick.to_string());
+        let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+        assert!(err.contains("Whitespace"), "{}", err);
+    }
+}
+
+#[test]
+fn duplicate_tip_labels_are_rejected() {
+    let mut t = NTree::new(1, "((A:0.1,B:0.2)X:0.1,(A:0.3,C:0.4)X:0.2);"
+        .to_string());
+    let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+    assert!(err.contains("'A' appears more than once"), "{}", err);
+
+    // Internal labels, e.g. support values, may repeat
+    let t = tree("((A:0.1,B:0.2)90:0.1,(C:0.3,D:0.4)90:0.2);", 1);
+    assert_eq!(t.n_tips(), 4);
+}
+
+#[test]
+fn bracket_comments_are_skipped() {
+    let t = tree("[&R] ((A[&rate=0.3]:0.1,B:0.2[&rate=1.5])X[x]:0.3,\
+        C:0.4[c]);", 1);
+    assert_eq!(t.output_ids(true), ["X", "A", "B", "C"]);
+    assert!((t.total_length() - 1.0).abs() < 1e-12);
+
+    let mut t = NTree::new(1, "((A:0.1,B:0.2[&rate=0.3):0.3,C:0.4);"
+        .to_string());
+    let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+    assert!(err.contains("Unterminated '['"), "{}", err);
+}
+
+#[test]
+fn branch_lengths_take_exponents() {
+    let t = tree("((A:1e-3,B:2.5E+2):1.5e-1,C:1.23e-4);", 1);
+    assert!((t.total_length() - 250.151123).abs() < 1e-9);
+    assert!((t.height() - 250.15).abs() < 1e-9);
+
+    let t = tree("(A:5E-10,B:2e0);", 1);
+    assert!((t.total_length() - (2.0 + 5e-10)).abs() < 1e-15);
+
+    for newick in ["(A:1e-,B:0.2);", "(A:1e--3,B:0.2);", "(A:1.5e3e2,B:1);"]
+        .iter() {
+        let mut t = NTree::new(1, newick.to_string());
+        let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+        assert!(err.contains("into branch"), "{}", err);
+    }
+}
+
+#[test]
+fn quoted_labels_keep_delimiters() {
+    let t = tree("('Homo sapiens (x)':0.1,'a:b,c;d':0.2,'O''Brien':0.3)\
+        'root [1]';", 1);
+    assert_eq!(t.output_ids(true),
+        ["root [1]", "Homo sapiens (x)", "a:b,c;d", "O'Brien"]);
+    assert!((t.total_length() - 0.6).abs() < 1e-12);
+}
+
+#[test]
+fn malformed_newick_is_an_error() {
+    let cases = [("(A:0.1,B:0.2)):0.3;", "extra ')'"),
+                 ("((A:0.1,B:0.2):0.3,C:0.4;", "missing 1 ')'"),
+                 ("(((A,B),C);", "missing 1 ')'"),
+                 (";", "Empty Newick tree")];
+    for (newick, msg) in cases.iter() {
+        let mut t = NTree::new(1, newick.to_string());
+        let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+        assert!(err.contains(msg), "{}: {}", newick, err);
+    }
+}
+
+#[test]
+fn unnamed_tips_get_the_same_ids_in_every_partition() {
+    let mut trees = vec![tree("((:0.1,:0.2):0.1,:0.3);", 10),
+                         tree("((:0.4,:0.1):0.2,:0.1);", 15)];
+    assert_eq!(trees[0].unnamed_tips(), 3);
+
+    let mut opts = SimulationOptions::new(2);
+    opts.auto_tip_ids = true;
+    let model = jc69();
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    assert_eq!(seqs.keys().collect::<Vec<_>>(), ["tip_1", "tip_2", "tip_3"]);
+    assert!(seqs.values().all(|s| s.len() == 25));
+
+    // Named tips keep their names, and their place in the numbering
+    let mut t = tree("((A:0.1,:0.2):0.1,:0.3);", 1);
+    t.label_tips();
+    assert_eq!(t.output_ids(false), ["A", "tip_2", "tip_3"]);
+}
+
+#[test]
+fn rescaling_puts_the_deepest_tip_at_the_target() {
+    let mut t = tree("((A:0.3,B:0.5):0.2,C:0.4);", 1);
+    t.rescale_to_height(2.0).unwrap();
+    assert!((t.height() - 2.0).abs() < 1e-12);
+    // Every branch is scaled by the same factor, 2 / 0.7
+    assert!((t.total_length() - 1.4 * 2.0 / 0.7).abs() < 1e-12);
+
+    let mut t = tree("(A:0,B:0);", 1);
+    assert!(t.rescale_to_height(1.0).is_err());
+}
+
+#[test]
+fn summary_statistics_of_a_small_tree() {
+    // Tip depths are A 0.5, B 0.7, C 0.4, D 1.0 and E 0.9
+    let t = tree("(((A:0.1,B:0.3):0.2,C:0.2):0.2,(D:0.6,E:0.5):0.4);", 1);
+    assert_eq!(t.n_tips(), 5);
+    assert!((t.height() - 1.0).abs() < 1e-12);
+    assert!((t.total_length() - 2.5).abs() < 1e-12);
+    assert!(t.is_binary());
+
+    let t = tree("(A:2);", 1);
+    assert_eq!(t.n_tips(), 1);
+    assert!((t.height() - 2.0).abs() < 1e-12);
+}
+
+#[test]
+fn negative_branches_are_errors_or_zero() {
+    let newick = "((A:-0.2,B:0.5)X:0.1,C:0.2);";
+    let mut t = NTree::new(200, newick.to_string());
+    let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+    assert!(err.contains("Negative branch length -0.2"), "{}", err);
+
+    let mut t = NTree::new(200, newick.to_string());
+    t.build_from_newick(NegativeBranches::Zero).unwrap();
+    assert_eq!(t.zeroed_branches(), 1);
+
+    let mut opts = SimulationOptions::new(8);
+    opts.ancestral = true;
+    let model = jc69();
+    let seqs = aminosim::simulate(&mut [t], &model, &opts).unwrap();
+    assert_eq!(seqs["A"], seqs["X"]);
+}
+
+#[test]
+fn branches_under_the_minimum_copy_their_parent() {
+    let mut trees = vec![tree("((A:1e-6,B:0.5)X:0.1,C:0.2);", 2000)];
+    let mut opts = SimulationOptions::new(6);
+    opts.ancestral = true;
+    opts.branch_min = 1e-3;
+
+    // Scaled up, A's branch would otherwise change a few sites
+    let model = JC69::new(b'A', b'G', b'C', b'T', 1e4);
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    assert_eq!(seqs["A"], seqs["X"]);
+    assert_ne!(seqs["B"], seqs["X"]);
+
+    opts.branch_min = 0.0;
+    let seqs = aminosim::simulate(&mut trees, &model, &opts).unwrap();
+    assert_ne!(seqs["A"], seqs["X"]);
+}
+
+#[test]
+fn nhx_attributes_are_kept() {
+    let t = tree("((A:0.1[&&NHX:S=human:E=1.1.1.1],B:0.2[&&NHX:S=chimp])\
+        AB:0.3[&&NHX:S=hominini:B=90:D=N],C:0.4[&rate=2]);", 1);
+
+    let a = t.attributes("A").unwrap();
+    assert_eq!(a.len(), 2);
+    assert_eq!(a["S"], "human");
+    assert_eq!(a["E"], "1.1.1.1");
+    assert_eq!(t.attributes("B").unwrap()["S"], "chimp");
+
+    let ab = t.attributes("AB").unwrap();
+    assert_eq!((ab["S"].as_str(), ab["B"].as_str(), ab["D"].as_str()),
+        ("hominini", "90", "N"));
+    // Other comments aren't attributes
+    assert!(t.attributes("C").unwrap().is_empty());
+
+    let mut t = NTree::new(1, "(A:0.1[&&NHX:S],B:0.2);".to_string());
+    let err = t.build_from_newick(NegativeBranches::Error).unwrap_err();
+    assert!(err.contains("Malformed NHX attribute"), "{}", err);
+}
+
+#[test]
+fn labeled_trees_survive_a_round_trip() {
+    let mut t = tree("((A:0.1,B:0.25)X:0.05,(C:0.3,D:0.4):0.125,E:1);", 1);
+    t.label_internal_nodes();
+
+    let again = tree(&t.to_newick(), 1);
+    assert_eq!(again.get_size(), t.get_size());
+    assert_eq!(again.output_ids(true), t.output_ids(true));
+    assert_eq!(again.output_ids(true),
+        ["node_0", "X", "A", "B", "node_2", "C", "D", "E"]);
+    assert_eq!(again.branch_substitutions(), t.branch_substitutions());
+}
+
+#[test]
+fn unlabeled_trees_survive_a_round_trip() {
+    let newick = "(((A:0.1,B:0.30000000000000004):2.5e-7,C:3),(D:1,E:0)):9;";
+    let t = tree(newick, 1);
+    let written = t.to_newick();
+    assert!(written.starts_with("((("));
+    assert!(written.ends_with(");"), "root got a branch in {}", written);
+
+    let again = tree(&written, 1);
+    assert_eq!(again.get_size(), t.get_size());
+    assert_eq!(again.output_ids(true), t.output_ids(true));
+
+    let before = t.branch_substitutions();
+    let after = again.branch_substitutions();
+    assert_eq!(before.len(), after.len());
+    for (b, a) in before.iter().zip(after.iter()) {
+        assert_eq!(b.0, a.0);
+        assert!((b.1 - a.1).abs() < 1e-12, "{} became {}", b.1, a.1);
+    }
+}
+
+#[test]
+fn branch_lengths_are_written_with_the_precision() {
+    let t = tree("((A:0.123456789,B:2):0.5,C:1e-7);", 1);
+    assert_eq!(t.to_newick_with_precision(3),
+        "((A:0.123,B:2.000):0.500,C:0.000);");
+    assert_eq!(t.to_newick_with_precision(8),
+        "((A:0.12345679,B:2.00000000):0.50000000,C:0.00000010);");
+}
+
+#[test]
+fn polytomies_are_resolved_with_zero_length_branches() {
+    let mut t = tree("((A:1,B:1,C:0.5):0.1,(D:1,E:1):0.2,F:1,G:2);", 1);
+    assert!(!t.is_binary());
+    assert_eq!(t.non_binary_nodes(), 2);
+    let (tips, height, length) = (t.output_ids(false), t.height(),
+        t.total_length());
+
+    assert_eq!(t.resolve_polytomies(), 2);
+    assert!(t.is_binary());
+    assert_eq!(t.output_ids(false), tips);
+    assert_eq!(t.height(), height);
+    assert_eq!(t.total_length(), length);
+    assert_eq!(t.resolve_polytomies(), 0);
+}
+
+#[test]
+fn repeated_internal_labels_are_found() {
+    assert_eq!(tree("((A:0.1,B:0.2)X:0.1,(C:0.3,D:0.4)X:0.2)R;", 1)
+        .repeated_id(), Some("X"));
+    assert_eq!(tree("((A:0.1,B:0.2)A:0.1,C:0.3);", 1).repeated_id(),
+        Some("A"));
+    assert_eq!(tree("((A:0.1,B:0.2)X:0.1,(C:0.3,D:0.4)Y:0.2)R;", 1)
+        .repeated_id(), None);
+}